@@ -1,7 +1,6 @@
 use smallvec::SmallVec;
 use std::collections::HashMap;
 use std::path::PathBuf;
-use toml;
 
 #[derive(Clone, Debug, Default)]
 pub struct Register {
@@ -19,13 +18,13 @@ impl Register {
     }
 
     pub fn get_main_name(&self) -> Option<&str> {
-        self.names.get(0).map(|x| x.as_ref())
+        self.names.first().map(|x| x.as_ref())
     }
 
     pub fn get_abi_name(&self) -> Option<&str> {
         self.names
             .get(1)
-            .or_else(|| self.names.get(0))
+            .or_else(|| self.names.first())
             .map(|x| x.as_ref())
     }
 }
@@ -53,12 +52,13 @@ impl BitRangeMap {
 
     pub fn value_bitmask(&self) -> u64 {
         let value_len = self.value_last - self.value_first + 1;
-        ((1 << value_len) - 1) << self.value_first
+        (u64::MAX >> (64 - value_len)) << self.value_first
     }
 
     pub fn encode_into(&self, bytes: &mut [u8], value: u64) {
-        let mut enc_value = (value & self.value_bitmask()) >> self.value_first;
-        let mut enc_mask = self.value_bitmask() >> self.value_first;
+        // 128 bits, so that 64-bit values don't lose bits shifting to a non-byte-aligned position
+        let mut enc_value = ((value & self.value_bitmask()) >> self.value_first) as u128;
+        let mut enc_mask = (self.value_bitmask() >> self.value_first) as u128;
         let mut instr_byte = self.instruction_first as usize / 8;
         enc_value <<= self.instruction_first as usize % 8;
         enc_mask <<= self.instruction_first as usize % 8;
@@ -77,6 +77,43 @@ impl BitRangeMap {
     }
 }
 
+/// Length in bytes of an instruction according to the RISC-V length encoding in its lowest bits,
+/// `bit(i)` returns the i-th bit of the instruction or None if it's not known.
+/// Returns None if a needed bit isn't known, Some(None) for the reserved >=192-bit encoding.
+fn decode_instruction_length<F: Fn(usize) -> Option<bool>>(bit: F) -> Option<Option<usize>> {
+    let ones = |from: usize, to: usize| -> Option<bool> {
+        let mut all = true;
+        for i in from..=to {
+            all &= bit(i)?;
+        }
+        Some(all)
+    };
+    if !ones(0, 1)? {
+        Some(Some(2))
+    } else if !ones(2, 4)? {
+        Some(Some(4))
+    } else if !bit(5)? {
+        Some(Some(6))
+    } else if !bit(6)? {
+        Some(Some(8))
+    } else {
+        let nnn =
+            (12..=14).try_fold(0, |acc, i| bit(i).map(|b| acc | ((b as usize) << (i - 12))))?;
+        if nnn != 0b111 {
+            Some(Some(10 + 2 * nnn))
+        } else {
+            Some(None)
+        }
+    }
+}
+
+/// Length in bytes of the encoded instruction at the start of `bytes`, see the
+/// "Expanded Instruction-Length Encoding" section of the unprivileged spec.
+/// None if there are too few bytes to tell or for the reserved >=192-bit encoding.
+pub fn encoded_instruction_length(bytes: &[u8]) -> Option<usize> {
+    decode_instruction_length(|i| bytes.get(i / 8).map(|b| (b >> (i % 8)) & 1 != 0)).flatten()
+}
+
 #[derive(Copy, Clone, Debug)]
 pub enum FieldType {
     Register,
@@ -129,6 +166,8 @@ impl InstructionFormat {
 #[derive(Clone, Debug, Default)]
 pub struct InstructionDefinition {
     pub name: String,
+    /// Code of the spec file (extension) this instruction was defined in
+    pub extension: String,
     pub format_idx: usize,
     /// Indices into InstructionFormat.fields
     pub args: Vec<usize>,
@@ -148,6 +187,30 @@ impl InstructionDefinition {
         spec.get_instruction_format(self.format_idx).unwrap()
     }
 
+    /// Checks that the length encoded by the fixed fields in the low bits matches the format,
+    /// instructions that leave those bits to arguments can't be checked
+    fn check_length_encoding(&self, fmt: &InstructionFormat) -> Result<(), usize> {
+        let ilen_bytes = fmt.ilen.div_ceil(8);
+        let mut bytes = vec![0u8; ilen_bytes.max(2)];
+        let mut mask = vec![0u8; ilen_bytes.max(2)];
+        for (fldid, fldval) in self.fields.iter() {
+            for e in fmt.fields[*fldid].encoding.iter() {
+                e.encode_into(&mut bytes, *fldval);
+                e.encode_into(&mut mask, u64::MAX);
+            }
+        }
+        let bit = |i: usize| {
+            let known = mask.get(i / 8)? >> (i % 8) & 1 != 0;
+            let value = bytes.get(i / 8)? >> (i % 8) & 1 != 0;
+            known.then_some(value)
+        };
+        match decode_instruction_length(bit) {
+            Some(Some(len)) if len != ilen_bytes.div_ceil(2) * 2 => Err(len),
+            Some(None) => Err(0),
+            _ => Ok(()),
+        }
+    }
+
     pub fn encode_into(
         &self,
         bytes: &mut [u8],
@@ -184,6 +247,7 @@ pub struct RiscVSpec {
     registers: HashMap<i32, Register>,
     register_name_lookup: HashMap<String, i32>,
     // Instruction formats
+    shared_fields: HashMap<String, InstructionField>,
     instruction_formats: Vec<InstructionFormat>,
     // Instructions
     instructions: Vec<InstructionDefinition>,
@@ -198,7 +262,7 @@ pub struct AbiFileInfo<'a> {
 
 // Main functionality
 impl RiscVSpec {
-    pub fn get_loaded_abis(&self) -> Vec<AbiFileInfo> {
+    pub fn get_loaded_abis(&self) -> Vec<AbiFileInfo<'_>> {
         let mut v = Vec::new();
         assert_eq!(self.loaded_names.len(), self.loaded_codes.len());
         assert_eq!(self.loaded_names.len(), self.loaded_specs.len());
@@ -219,6 +283,10 @@ impl RiscVSpec {
         self.consts.get(name).copied()
     }
 
+    pub fn get_all_consts(&self) -> &HashMap<String, u64> {
+        &self.consts
+    }
+
     // Registers
 
     pub fn get_register(&self, rnum: i32) -> Option<&Register> {
@@ -237,6 +305,10 @@ impl RiscVSpec {
 
     // Instruction Formats
 
+    pub fn get_shared_field(&self, name: &str) -> Option<&InstructionField> {
+        self.shared_fields.get(name)
+    }
+
     pub fn get_instruction_format(&self, index: usize) -> Option<&InstructionFormat> {
         self.instruction_formats.get(index)
     }
@@ -271,15 +343,173 @@ impl RiscVSpec {
 
 #[derive(Clone, Debug)]
 pub enum LoadError {
-    MalformedTOML,
+    /// Message from the TOML parser and the 0-based line/column it points at
+    MalformedTOML(String, Option<(usize, usize)>),
+    /// File that could not be read and the OS error message
+    Io(PathBuf, String),
     InvalidArchSpec,
     DependencyCycle,
     RequirementNotFound(String),
-    ConstNotFound(String),
+    /// Key path and the name of the missing const
+    ConstNotFound(String, String),
     MissingNode(String),
     BadType(String),
+    /// Key path and the reason the expression could not be evaluated
+    BadExpression(String, String),
     DuplicateInstruction(String),
-    BadInstructionFormat(String),
+    /// Key path and the name of the unknown format
+    BadInstructionFormat(String, String),
+    /// Key path and the name of the field missing from the format
+    BadInstructionField(String, String),
+    /// Key path, the length in bytes encoded by the opcode bits (0 if reserved) and the format's length in bits
+    BadLengthEncoding(String, usize, usize),
+    /// Instruction name and the riscv-opcodes argument that has no known encoding
+    UnknownOpcodesArgument(String, String),
+    /// Instruction name and its riscv-opcodes arguments that don't fit the assembler syntax
+    /// of its kind of instruction
+    UnmappedOpcodesArguments(String, Vec<String>),
+    /// Wraps another error with the file it came from and the 0-based line it was located at
+    Located(PathBuf, Option<usize>, Box<LoadError>),
+}
+
+impl LoadError {
+    /// Dotted TOML key path the error refers to, if any
+    pub fn key_path(&self) -> Option<&str> {
+        use LoadError::*;
+        match self {
+            ConstNotFound(key, _)
+            | MissingNode(key)
+            | BadType(key)
+            | BadExpression(key, _)
+            | BadInstructionFormat(key, _)
+            | BadInstructionField(key, _)
+            | BadLengthEncoding(key, _, _) => Some(key),
+            Located(_, _, e) => e.key_path(),
+            _ => None,
+        }
+    }
+
+    /// Attaches the file path and a best-effort line number found in the file's contents
+    fn located(self, path: &std::path::Path, content: &str) -> Self {
+        if let LoadError::Located(..) = self {
+            return self;
+        }
+        let line = match &self {
+            LoadError::MalformedTOML(_, lc) => lc.map(|(l, _)| l),
+            LoadError::RequirementNotFound(_) | LoadError::DependencyCycle => {
+                find_key_line(content, "meta.requires")
+            }
+            e => e.key_path().and_then(|k| find_key_line(content, k)),
+        };
+        LoadError::Located(path.to_owned(), line, Box::new(self))
+    }
+}
+
+impl std::fmt::Display for LoadError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        use LoadError::*;
+        match self {
+            MalformedTOML(msg, _) => write!(f, "malformed TOML: {}", msg),
+            Io(path, msg) => write!(f, "{}: {}", path.display(), msg),
+            InvalidArchSpec => write!(f, "invalid architecture specification"),
+            DependencyCycle => write!(f, "dependency cycle between spec files"),
+            RequirementNotFound(code) => write!(f, "required spec '{}' not found", code),
+            ConstNotFound(key, name) => write!(f, "{}: unknown const '{}'", key, name),
+            MissingNode(key) => write!(f, "{}: missing", key),
+            BadType(key) => write!(f, "{}: bad type", key),
+            BadExpression(key, msg) => write!(f, "{}: {}", key, msg),
+            DuplicateInstruction(name) => write!(f, "instructions.{}: duplicate instruction", name),
+            BadInstructionFormat(key, name) => write!(f, "{}: unknown format '{}'", key, name),
+            BadInstructionField(key, name) => {
+                write!(f, "{}: unknown field '{}'", key, name)
+            }
+            UnknownOpcodesArgument(insn, arg) => {
+                write!(f, "{}: unknown riscv-opcodes argument '{}'", insn, arg)
+            }
+            UnmappedOpcodesArguments(insn, args) => write!(
+                f,
+                "{}: riscv-opcodes arguments '{}' can't be put in assembler order",
+                insn,
+                args.join(" ")
+            ),
+            BadLengthEncoding(key, 0, ilen) => write!(
+                f,
+                "{}: opcode bits use the reserved length encoding for a {}-bit format",
+                key, ilen
+            ),
+            BadLengthEncoding(key, len, ilen) => write!(
+                f,
+                "{}: opcode bits encode a {}-bit length for a {}-bit format",
+                key,
+                len * 8,
+                ilen
+            ),
+            Located(path, Some(line), e) => write!(f, "{}:{}: {}", path.display(), line + 1, e),
+            Located(path, None, e) => write!(f, "{}: {}", path.display(), e),
+        }
+    }
+}
+
+impl std::error::Error for LoadError {}
+
+/// Splits a key path as used in LoadError messages into plain TOML key segments,
+/// dropping index suffixes like `[]` and trailing descriptions like ` key`
+fn key_path_segments(key: &str) -> Vec<String> {
+    let key = key.split_whitespace().next().unwrap_or("");
+    key.split(['.', '[', ']'])
+        .filter(|s| !s.is_empty())
+        .map(|s| s.trim_matches('"').to_ascii_lowercase())
+        .collect()
+}
+
+/// Best-effort search for the 0-based line defining the given key path.
+/// toml 0.5 values don't carry spans, so this tracks table headers and key assignments
+/// line by line, picking the first line that matches the most leading segments.
+fn find_key_line(content: &str, key: &str) -> Option<usize> {
+    let target = key_path_segments(key);
+    let mut table: Vec<String> = Vec::new();
+    let mut best: Option<(usize, usize)> = None;
+    for (lineno, line) in content.lines().enumerate() {
+        let line = line.split('#').next().unwrap_or("").trim();
+        if line.is_empty() {
+            continue;
+        }
+        let mut path;
+        let mut rest = "";
+        if line.starts_with('[') {
+            table = key_path_segments(line.trim_matches(|c| c == '[' || c == ']'));
+            path = table.clone();
+        } else if let Some(eq) = line.find('=') {
+            path = table.clone();
+            path.extend(key_path_segments(line[..eq].trim()));
+            rest = &line[eq + 1..];
+        } else {
+            continue;
+        }
+        let mut matched = path
+            .iter()
+            .zip(target.iter())
+            .take_while(|(a, b)| a == b)
+            .count();
+        if matched < path.len() {
+            continue;
+        }
+        // keys of inline tables on the same line
+        for seg in target.iter().skip(matched) {
+            let lrest = rest.to_ascii_lowercase();
+            match lrest
+                .find(&format!("{} ", seg))
+                .or_else(|| lrest.find(&format!("{}=", seg)))
+            {
+                Some(_) => matched += 1,
+                None => break,
+            }
+        }
+        if best.is_none_or(|(m, _)| matched > m) {
+            best = Some((matched, lineno));
+        }
+    }
+    best.filter(|(m, _)| *m > 0).map(|(_, l)| l)
 }
 
 // Creation & Parsing
@@ -294,8 +524,10 @@ impl RiscVSpec {
     }
 
     pub fn load_single_cfg_file(&mut self, path: &std::path::Path) -> Result<(), LoadError> {
-        let content = std::fs::read_to_string(path).map_err(|_| LoadError::InvalidArchSpec)?;
+        let content = std::fs::read_to_string(path)
+            .map_err(|e| LoadError::Io(path.to_owned(), e.to_string()))?;
         self.load_single_cfg_string(&content)
+            .map_err(|e| e.located(path, &content))
     }
 
     pub fn load_arch_cfg(
@@ -322,10 +554,12 @@ impl RiscVSpec {
             let mut loaded = false;
             for path in std_paths.iter() {
                 let mut p: PathBuf = path.clone();
-                p.push(&fp.as_str().to_ascii_lowercase());
+                p.push(fp.as_str().to_ascii_lowercase());
                 p.set_extension("toml");
                 if let Ok(strdata) = std::fs::read_to_string(&p) {
-                    docs.push(Self::string_to_toml(&strdata)?);
+                    let doc =
+                        Self::string_to_toml(&strdata).map_err(|e| e.located(&p, &strdata))?;
+                    docs.push((p.clone(), strdata, doc));
                     if verbose {
                         let pstr = p.as_os_str().to_string_lossy();
                         eprintln!("Found {} spec in {}", fp.as_str(), pstr);
@@ -344,51 +578,36 @@ impl RiscVSpec {
             StableGraph::with_capacity(docs.len(), docs.len());
         let mut codes = HashMap::new();
         let mut nodes = Vec::new();
-        for (i, doc) in docs.iter().enumerate() {
-            let meta = doc
-                .get("meta")
-                .ok_or_else(|| LoadError::MissingNode("meta".to_owned()))?;
-            let code = meta
-                .get("code")
-                .ok_or_else(|| LoadError::MissingNode("meta.code".to_owned()))?
-                .as_str()
-                .ok_or_else(|| LoadError::BadType("meta.code".to_owned()))?;
+        for (i, (path, content, doc)) in docs.iter().enumerate() {
+            let code = Self::toml_meta_code(doc).map_err(|e| e.located(path, content))?;
             let nidx = depgraph.add_node(i);
             codes.insert(code.to_owned(), nidx);
             nodes.push(nidx);
         }
-        for (i, doc) in docs.iter().enumerate() {
-            let meta = doc
-                .get("meta")
-                .ok_or_else(|| LoadError::MissingNode("meta".to_owned()))?;
-            let requires = meta.get("requires");
-            if let Some(requires) = requires {
-                let requires = requires
-                    .as_array()
-                    .ok_or_else(|| LoadError::BadType("meta.requires".to_owned()))?;
-                for rq in requires.iter() {
-                    let code = rq
-                        .as_str()
-                        .ok_or_else(|| LoadError::BadType("meta.requires item".to_owned()))?;
-                    let other = codes.get(code);
-                    if other.is_none() {
-                        return Err(LoadError::RequirementNotFound(code.to_owned()));
-                    }
-                    let nidx = nodes[i];
-                    let oidx = *other.unwrap();
-                    depgraph.add_edge(nidx, oidx, ());
+        for (i, (path, content, doc)) in docs.iter().enumerate() {
+            let requires = Self::toml_meta_requires(doc).map_err(|e| e.located(path, content))?;
+            for code in requires {
+                let other = codes.get(code);
+                if other.is_none() {
+                    return Err(
+                        LoadError::RequirementNotFound(code.to_owned()).located(path, content)
+                    );
                 }
+                let nidx = nodes[i];
+                let oidx = *other.unwrap();
+                depgraph.add_edge(nidx, oidx, ());
             }
         }
 
         // resolve graph
-        let res = petgraph::algo::toposort(&depgraph, None);
-        if res.is_err() {
-            return Err(LoadError::DependencyCycle);
-        }
-        let res = res.unwrap();
+        let res = petgraph::algo::toposort(&depgraph, None).map_err(|cycle| {
+            let (path, content, _) = &docs[depgraph[cycle.node_id()]];
+            LoadError::DependencyCycle.located(path, content)
+        })?;
         for node in res.iter().rev() {
-            self.load_single_toml(&docs[depgraph[*node]])?;
+            let (path, content, doc) = &docs[depgraph[*node]];
+            self.load_single_toml(doc)
+                .map_err(|e| e.located(path, content))?;
         }
 
         Ok(())
@@ -396,12 +615,46 @@ impl RiscVSpec {
 
     fn string_to_toml(content: &str) -> Result<toml::Value, LoadError> {
         use toml::Value;
-        content
-            .parse::<Value>()
-            .map_err(|_| LoadError::MalformedTOML)
+        content.parse::<Value>().map_err(|e| {
+            // the line is reported with the file instead of in the parser's message
+            let msg = e.to_string();
+            let msg = match e.line_col() {
+                Some(_) => msg.rsplit_once(" at line ").map_or(&msg[..], |m| m.0),
+                None => &msg,
+            };
+            LoadError::MalformedTOML(msg.to_owned(), e.line_col())
+        })
     }
 
-    /// Load integer or search in consts if a string
+    fn toml_meta_code(doc: &toml::Value) -> Result<&str, LoadError> {
+        doc.get("meta")
+            .ok_or_else(|| LoadError::MissingNode("meta".to_owned()))?
+            .get("code")
+            .ok_or_else(|| LoadError::MissingNode("meta.code".to_owned()))?
+            .as_str()
+            .ok_or_else(|| LoadError::BadType("meta.code".to_owned()))
+    }
+
+    fn toml_meta_requires(doc: &toml::Value) -> Result<Vec<&str>, LoadError> {
+        let meta = doc
+            .get("meta")
+            .ok_or_else(|| LoadError::MissingNode("meta".to_owned()))?;
+        let mut v = Vec::new();
+        if let Some(requires) = meta.get("requires") {
+            let requires = requires
+                .as_array()
+                .ok_or_else(|| LoadError::BadType("meta.requires".to_owned()))?;
+            for rq in requires.iter() {
+                v.push(
+                    rq.as_str()
+                        .ok_or_else(|| LoadError::BadType("meta.requires item".to_owned()))?,
+                );
+            }
+        }
+        Ok(v)
+    }
+
+    /// Load integer or evaluate a string as an expression over the consts
     fn toml_int(
         consts: &HashMap<String, u64>,
         key: String,
@@ -410,15 +663,130 @@ impl RiscVSpec {
         if let Some(i) = v.as_integer() {
             Ok(i)
         } else if let Some(s) = v.as_str() {
-            consts
-                .get(s)
-                .ok_or_else(|| LoadError::ConstNotFound(s.to_owned()))
-                .map(|x| *x as i64)
+            Self::eval_const_expr(consts, key, s).map(|x| x as i64)
         } else {
             Err(LoadError::BadType(key))
         }
     }
 
+    /// Parses a field definition table like `{ type = "value", length = 7, encoding = [[6,0,0]] }`
+    fn toml_field(
+        consts: &HashMap<String, u64>,
+        key: &str,
+        name: &str,
+        fldtable: &toml::value::Table,
+    ) -> Result<InstructionField, LoadError> {
+        let mut fld = InstructionField {
+            name: name.to_owned(),
+            vtype: FieldType::Value,
+            length: 0,
+            encoding: Default::default(),
+        };
+        let fldtype = fldtable
+            .get("type")
+            .ok_or_else(|| LoadError::MissingNode(format!("{}.type", key)))?
+            .as_str()
+            .ok_or_else(|| LoadError::BadType(format!("{}.type", key)))?;
+        match fldtype {
+            "value" => {
+                fld.vtype = FieldType::Value;
+            }
+            "register" => {
+                fld.vtype = FieldType::Register;
+            }
+            _ => {
+                return Err(LoadError::BadType(format!("{}.type", key)));
+            }
+        }
+        fld.length = Self::toml_int(
+            consts,
+            format!("{}.length", key),
+            fldtable
+                .get("length")
+                .ok_or_else(|| LoadError::MissingNode(format!("{}.length", key)))?,
+        )? as i32;
+        let fldencoding = fldtable
+            .get("encoding")
+            .ok_or_else(|| LoadError::MissingNode(format!("{}.encoding", key)))?
+            .as_array()
+            .ok_or_else(|| LoadError::BadType(format!("{}.encoding", key)))?;
+        for val in fldencoding.iter() {
+            let subenc = val
+                .as_array()
+                .ok_or_else(|| LoadError::BadType(format!("{}.encoding[] element", key)))?;
+            if subenc.len() != 3 {
+                return Err(LoadError::BadType(format!(
+                    "{}.encoding[][] length (must be 3)",
+                    key
+                )));
+            }
+            let vend = Self::toml_int(consts, format!("{}.encoding[][]", key), &subenc[0])? as i32;
+            let vbegin =
+                Self::toml_int(consts, format!("{}.encoding[][]", key), &subenc[1])? as i32;
+            let ibegin =
+                Self::toml_int(consts, format!("{}.encoding[][]", key), &subenc[2])? as i32;
+
+            fld.encoding.push(BitRangeMap::new(vend, vbegin, ibegin));
+        }
+        Ok(fld)
+    }
+
+    /// Evaluates the optional `when` guard of a table, true if absent
+    fn toml_when(
+        consts: &HashMap<String, u64>,
+        key: String,
+        table: &toml::value::Table,
+    ) -> Result<bool, LoadError> {
+        match table.get("when") {
+            None => Ok(true),
+            Some(toml::Value::Boolean(b)) => Ok(*b),
+            Some(toml::Value::String(s)) => {
+                Self::eval_const_expr(consts, format!("{}.when", key), s).map(|v| v != 0)
+            }
+            Some(_) => Err(LoadError::BadType(format!("{}.when", key))),
+        }
+    }
+
+    /// Optional `name` of a table, for defining guarded variants of the same instruction or format
+    fn toml_name_override(
+        key: String,
+        table: &toml::value::Table,
+    ) -> Result<Option<&str>, LoadError> {
+        table
+            .get("name")
+            .map(|n| {
+                n.as_str()
+                    .ok_or_else(|| LoadError::BadType(format!("{}.name", key)))
+            })
+            .transpose()
+    }
+
+    /// Evaluates an expression like `XLEN - 1` with the assembler's expression engine,
+    /// identifiers are looked up in the consts
+    fn eval_const_expr(
+        consts: &HashMap<String, u64>,
+        key: String,
+        expr: &str,
+    ) -> Result<u64, LoadError> {
+        use crate::parser::Node;
+        // an empty spec, so that no identifier is taken for a register name
+        let node = crate::grammar::expression(expr, &RiscVSpec::new())
+            .map_err(|e| LoadError::BadExpression(key.clone(), e.to_string()))?;
+        match node
+            .emitter_simplify(&|c: &str| consts.get(c).copied(), 0)
+            .map_err(|e| LoadError::BadExpression(key.clone(), e.to_string()))?
+        {
+            (Node::Integer(v), _) => Ok(v),
+            (node, _) => match node.identifiers().first() {
+                Some(name) => Err(LoadError::ConstNotFound(key, (*name).to_owned())),
+                None => Err(LoadError::BadExpression(
+                    key,
+                    format!("'{}' does not evaluate to an integer", expr),
+                )),
+            },
+        }
+    }
+
     fn load_single_toml(&mut self, doc: &toml::Value) -> Result<(), LoadError> {
         #[allow(non_snake_case)]
         let MissingNode = |s: &'static str| LoadError::MissingNode(s.to_owned());
@@ -428,6 +796,7 @@ impl RiscVSpec {
         let meta = doc.get("meta").ok_or_else(|| MissingNode("meta"))?;
         let consts = doc.get("consts");
         let registers = doc.get("registers");
+        let fields = doc.get("fields");
         let instruction_formats = doc.get("instruction_formats");
         let instructions = doc.get("instructions");
 
@@ -524,6 +893,22 @@ impl RiscVSpec {
             }
         }
 
+        // parse shared fields
+        if let Some(fields) = fields {
+            let fields = fields.as_table().ok_or_else(|| BadType("fields"))?;
+            for (fldname, fldtable) in fields.iter() {
+                let fldkey = format!("fields.{}", fldname);
+                let fldtable = fldtable
+                    .as_table()
+                    .ok_or_else(|| LoadError::BadType(fldkey.clone()))?;
+                if !Self::toml_when(&self.consts, fldkey.clone(), fldtable)? {
+                    continue;
+                }
+                let fld = Self::toml_field(&self.consts, &fldkey, fldname, fldtable)?;
+                self.shared_fields.insert(fldname.to_owned(), fld);
+            }
+        }
+
         // parse instruction_formats
         if let Some(instruction_formats) = instruction_formats {
             let instruction_formats = instruction_formats
@@ -533,103 +918,67 @@ impl RiscVSpec {
                 let fmttable = fmttable.as_table().ok_or_else(|| {
                     LoadError::BadType(format!("instruction_formats.{}", fmtname))
                 })?;
-                let mut fmt = InstructionFormat::new(fmtname.to_owned());
-                for (fldname, fldtable) in fmttable.iter() {
-                    let fldtable = fldtable.as_table().ok_or_else(|| {
-                        LoadError::BadType(format!("instruction_formats.{}.{}", fmtname, fldname))
-                    })?;
-                    let mut fld = InstructionField {
-                        name: fldname.to_owned(),
-                        vtype: FieldType::Value,
-                        length: 0,
-                        encoding: Default::default(),
-                    };
-                    let fldtype = fldtable
-                        .get("type")
-                        .ok_or_else(|| {
-                            LoadError::MissingNode(format!(
-                                "instruction_formats.{}.{}.type",
-                                fmtname, fldname
-                            ))
-                        })?
+                if !Self::toml_when(
+                    &self.consts,
+                    format!("instruction_formats.{}", fmtname),
+                    fmttable,
+                )? {
+                    continue;
+                }
+                let mut fmt = InstructionFormat::new(
+                    Self::toml_name_override(format!("instruction_formats.{}", fmtname), fmttable)?
+                        .unwrap_or(fmtname)
+                        .to_owned(),
+                );
+                // start off with a copy of the base format's fields
+                if let Some(base) = fmttable.get("extends") {
+                    let basekey = format!("instruction_formats.{}.extends", fmtname);
+                    let base = base
                         .as_str()
-                        .ok_or_else(|| {
-                            LoadError::BadType(format!(
-                                "instruction_formats.{}.{}.type",
-                                fmtname, fldname
-                            ))
-                        })?;
-                    match fldtype {
-                        "value" => {
-                            fld.vtype = FieldType::Value;
+                        .ok_or_else(|| LoadError::BadType(basekey.clone()))?;
+                    fmt.fields = self
+                        .instruction_formats
+                        .iter()
+                        .find(|x| x.name == base)
+                        .ok_or_else(|| LoadError::BadInstructionFormat(basekey, base.to_owned()))?
+                        .fields
+                        .clone();
+                }
+                for (fldname, fldval) in fmttable.iter() {
+                    if fldname == "when" || fldname == "name" || fldname == "extends" {
+                        continue;
+                    }
+                    let fldkey = format!("instruction_formats.{}.{}", fmtname, fldname);
+                    let fld = match fldval {
+                        // removes an inherited field
+                        toml::Value::Boolean(false) => {
+                            fmt.fields.retain(|f| f.name != *fldname);
+                            continue;
                         }
-                        "register" => {
-                            fld.vtype = FieldType::Register;
+                        // refers to a field from a [fields] table
+                        toml::Value::String(shared) => {
+                            let mut fld = self
+                                .shared_fields
+                                .get(shared)
+                                .ok_or_else(|| {
+                                    LoadError::BadInstructionField(fldkey, shared.to_owned())
+                                })?
+                                .clone();
+                            fld.name = fldname.to_owned();
+                            fld
                         }
-                        _ => {
-                            return Err(LoadError::BadType(format!(
-                                "instruction_formats.{}.{}.type",
-                                fmtname, fldname
-                            )));
+                        toml::Value::Table(fldtable) => {
+                            if !Self::toml_when(&self.consts, fldkey.clone(), fldtable)? {
+                                continue;
+                            }
+                            Self::toml_field(&self.consts, &fldkey, fldname, fldtable)?
                         }
+                        _ => return Err(LoadError::BadType(fldkey)),
+                    };
+                    match fmt.fields.iter_mut().find(|f| f.name == fld.name) {
+                        Some(existing) => *existing = fld,
+                        None => fmt.fields.push(fld),
                     }
-                    fld.length = Self::toml_int(
-                        &self.consts,
-                        format!("instruction_formats.{}.{}.length", fmtname, fldname),
-                        fldtable.get("length").ok_or_else(|| {
-                            LoadError::MissingNode(format!(
-                                "instruction_formats.{}.{}.length",
-                                fmtname, fldname
-                            ))
-                        })?,
-                    )? as i32;
-                    let fldencoding = fldtable
-                        .get("encoding")
-                        .ok_or_else(|| {
-                            LoadError::MissingNode(format!(
-                                "instruction_formats.{}.{}.encoding",
-                                fmtname, fldname
-                            ))
-                        })?
-                        .as_array()
-                        .ok_or_else(|| {
-                            LoadError::BadType(format!(
-                                "instruction_formats.{}.{}.encoding",
-                                fmtname, fldname
-                            ))
-                        })?;
-                    for val in fldencoding.iter() {
-                        let subenc = val.as_array().ok_or_else(|| {
-                            LoadError::BadType(format!(
-                                "instruction_formats.{}.{}.encoding[] element",
-                                fmtname, fldname
-                            ))
-                        })?;
-                        if subenc.len() != 3 {
-                            return Err(LoadError::BadType(format!(
-                                "instruction_formats.{}.{}.encoding[][] length (must be 3)",
-                                fmtname, fldname
-                            )));
-                        }
-                        let vend = Self::toml_int(
-                            &self.consts,
-                            format!("instruction_formats.{}.{}.encoding[][]", fmtname, fldname),
-                            &subenc[0],
-                        )? as i32;
-                        let vbegin = Self::toml_int(
-                            &self.consts,
-                            format!("instruction_formats.{}.{}.encoding[][]", fmtname, fldname),
-                            &subenc[1],
-                        )? as i32;
-                        let ibegin = Self::toml_int(
-                            &self.consts,
-                            format!("instruction_formats.{}.{}.encoding[][]", fmtname, fldname),
-                            &subenc[2],
-                        )? as i32;
-
-                        fld.encoding.push(BitRangeMap::new(vend, vbegin, ibegin));
-                    }
-                    fmt.fields.push(fld);
                 }
                 fmt.ilen = fmt.calculate_last_encoded_bit_index() as usize + 1;
                 self.instruction_formats.push(fmt);
@@ -641,48 +990,53 @@ impl RiscVSpec {
             let instructions = instructions
                 .as_table()
                 .ok_or_else(|| BadType("instructions"))?;
-            for (iname, itable) in instructions.iter() {
-                let iname = iname.to_ascii_lowercase();
+            for (ikey, itable) in instructions.iter() {
+                let ikey = ikey.to_ascii_lowercase();
                 let itable = itable
                     .as_table()
-                    .ok_or_else(|| LoadError::BadType(format!("instructions.{}", iname)))?;
+                    .ok_or_else(|| LoadError::BadType(format!("instructions.{}", ikey)))?;
+                if !Self::toml_when(&self.consts, format!("instructions.{}", ikey), itable)? {
+                    continue;
+                }
+                let iname = Self::toml_name_override(format!("instructions.{}", ikey), itable)?
+                    .map_or_else(|| ikey.clone(), |n| n.to_ascii_lowercase());
 
                 let iformat = itable
                     .get("format")
-                    .ok_or_else(|| {
-                        LoadError::MissingNode(format!("instructions.{}.format", iname))
-                    })?
+                    .ok_or_else(|| LoadError::MissingNode(format!("instructions.{}.format", ikey)))?
                     .as_str()
-                    .ok_or_else(|| LoadError::BadType(format!("instructions.{}.format", iname)))?;
+                    .ok_or_else(|| LoadError::BadType(format!("instructions.{}.format", ikey)))?;
 
                 let iargs = itable
                     .get("args")
-                    .ok_or_else(|| LoadError::MissingNode(format!("instructions.{}.args", iname)))?
+                    .ok_or_else(|| LoadError::MissingNode(format!("instructions.{}.args", ikey)))?
                     .as_array()
-                    .ok_or_else(|| LoadError::BadType(format!("instructions.{}.args", iname)))?;
+                    .ok_or_else(|| LoadError::BadType(format!("instructions.{}.args", ikey)))?;
 
                 let ifields = itable
                     .get("fields")
-                    .ok_or_else(|| {
-                        LoadError::MissingNode(format!("instructions.{}.fields", iname))
-                    })?
+                    .ok_or_else(|| LoadError::MissingNode(format!("instructions.{}.fields", ikey)))?
                     .as_table()
-                    .ok_or_else(|| LoadError::BadType(format!("instructions.{}.fields", iname)))?;
+                    .ok_or_else(|| LoadError::BadType(format!("instructions.{}.fields", ikey)))?;
 
                 let mut insn = InstructionDefinition::new(iname.clone());
+                insn.extension = self.loaded_codes.last().unwrap().clone();
 
                 insn.format_idx = self
                     .instruction_formats
                     .iter()
                     .position(|x| x.name == iformat)
                     .ok_or_else(|| {
-                        LoadError::BadInstructionFormat(format!("instructions.{}.format", iname))
+                        LoadError::BadInstructionFormat(
+                            format!("instructions.{}.format", ikey),
+                            iformat.to_owned(),
+                        )
                     })?;
                 let fmt = &self.instruction_formats[insn.format_idx];
 
                 for argv in iargs.iter() {
                     let argv = argv.as_str().ok_or_else(|| {
-                        LoadError::BadType(format!("instructions.{}.args[] item", iname))
+                        LoadError::BadType(format!("instructions.{}.args[] item", ikey))
                     })?;
                     insn.args
                         .push(
@@ -690,10 +1044,10 @@ impl RiscVSpec {
                                 .iter()
                                 .position(|x| x.name == argv)
                                 .ok_or_else(|| {
-                                    LoadError::BadInstructionFormat(format!(
-                                        "instructions.{}.args[{}]",
-                                        iname, argv
-                                    ))
+                                    LoadError::BadInstructionField(
+                                        format!("instructions.{}.args", ikey),
+                                        argv.to_owned(),
+                                    )
                                 })?,
                         );
                 }
@@ -701,7 +1055,7 @@ impl RiscVSpec {
                 for (fname, fv) in ifields.iter() {
                     let fv = Self::toml_int(
                         &self.consts,
-                        format!("instructions.{}.fields[{}]", iname, fname),
+                        format!("instructions.{}.fields[{}]", ikey, fname),
                         fv,
                     )?;
                     let fi = fmt
@@ -709,13 +1063,16 @@ impl RiscVSpec {
                         .iter()
                         .position(|x| x.name == fname.as_ref())
                         .ok_or_else(|| {
-                            LoadError::BadInstructionFormat(format!(
-                                "instructions.{}.fields[{}]",
-                                iname, fname
-                            ))
+                            LoadError::BadInstructionField(
+                                format!("instructions.{}.fields.{}", ikey, fname),
+                                fname.to_owned(),
+                            )
                         })?;
                     insn.fields.push((fi, fv as u64));
                 }
+                insn.check_length_encoding(fmt).map_err(|len| {
+                    LoadError::BadLengthEncoding(format!("instructions.{}", ikey), len, fmt.ilen)
+                })?;
 
                 if self
                     .instruction_name_lookup
@@ -738,3 +1095,276 @@ impl RiscVSpec {
         Ok(())
     }
 }
+
+/// Argument fields of the riscv-opcodes format mapped onto the field names used by rvasm's own specs:
+/// (riscv-opcodes name, field name, type, length, encoding as [vlast, vfirst, ifirst] triples).
+/// Split immediates like `imm12hi`/`imm12lo` map onto a single field so they form one argument.
+/// Lengths are those of the value, like `bimm12` offsets being 13 bits wide: `imm20` is taken
+/// in place like the `lui` of the TOML specs, and `jimm20` is a 21-bit offset.
+type OpcodesArg = (
+    &'static str,
+    &'static str,
+    FieldType,
+    i32,
+    &'static [(i32, i32, i32)],
+);
+#[rustfmt::skip]
+const OPCODES_ARGS: &[OpcodesArg] = &[
+    ("rd", "rd", FieldType::Register, 5, &[(4, 0, 7)]),
+    ("rs1", "rs1", FieldType::Register, 5, &[(4, 0, 15)]),
+    ("rs2", "rs2", FieldType::Register, 5, &[(4, 0, 20)]),
+    ("rs3", "rs3", FieldType::Register, 5, &[(4, 0, 27)]),
+    ("imm12", "imm", FieldType::Value, 12, &[(11, 0, 20)]),
+    ("imm12hi", "imm", FieldType::Value, 12, &[(4, 0, 7), (11, 5, 25)]),
+    ("imm12lo", "imm", FieldType::Value, 12, &[(4, 0, 7), (11, 5, 25)]),
+    ("bimm12hi", "imm", FieldType::Value, 13, &[(11, 11, 7), (4, 1, 8), (10, 5, 25), (12, 12, 31)]),
+    ("bimm12lo", "imm", FieldType::Value, 13, &[(11, 11, 7), (4, 1, 8), (10, 5, 25), (12, 12, 31)]),
+    ("imm20", "imm", FieldType::Value, 32, &[(31, 12, 12)]),
+    ("jimm20", "imm", FieldType::Value, 21, &[(19, 12, 12), (11, 11, 20), (10, 1, 21), (20, 20, 31)]),
+    ("shamt", "imm", FieldType::Value, 6, &[(5, 0, 20)]),
+    ("shamtw", "imm", FieldType::Value, 5, &[(4, 0, 20)]),
+    ("shamtd", "imm", FieldType::Value, 6, &[(5, 0, 20)]),
+    ("shamtq", "imm", FieldType::Value, 7, &[(6, 0, 20)]),
+    ("csr", "csr", FieldType::Value, 12, &[(11, 0, 20)]),
+    ("zimm", "zimm", FieldType::Value, 5, &[(4, 0, 15)]),
+    ("fm", "fm", FieldType::Value, 4, &[(3, 0, 28)]),
+    ("pred", "pred", FieldType::Value, 4, &[(3, 0, 24)]),
+    ("succ", "succ", FieldType::Value, 4, &[(3, 0, 20)]),
+    ("aq", "aq", FieldType::Value, 1, &[(0, 0, 26)]),
+    ("rl", "rl", FieldType::Value, 1, &[(0, 0, 25)]),
+    ("rm", "rm", FieldType::Value, 3, &[(2, 0, 12)]),
+];
+
+/// Major opcodes (instruction bits 6..2) of loads, which take their offset before the base
+/// register like stores
+const OPCODES_LOAD_MAJORS: &[u64] = &[0x00, 0x01];
+
+/// Argument order of the assembler syntax for memory accesses and branches, which riscv-opcodes
+/// lists in encoding order instead. The offset of a memory access comes before its base register
+/// (`sw rs2, imm, rs1` for `sw rs2, imm(rs1)`) and the target of a branch comes last.
+fn opcodes_assembler_order(tokens: &[&str], major: Option<u64>) -> Option<&'static [&'static str]> {
+    if tokens.contains(&"imm12hi") {
+        Some(&["rs2", "imm", "rs1"])
+    } else if tokens.contains(&"bimm12hi") {
+        Some(&["rs1", "rs2", "imm"])
+    } else if major.is_some_and(|m| OPCODES_LOAD_MAJORS.contains(&m)) && tokens.contains(&"imm12") {
+        Some(&["rd", "imm", "rs1"])
+    } else {
+        None
+    }
+}
+
+fn parse_opcodes_int(s: &str) -> Option<u64> {
+    if let Some(h) = s.strip_prefix("0x") {
+        u64::from_str_radix(h, 16).ok()
+    } else if let Some(b) = s.strip_prefix("0b") {
+        u64::from_str_radix(b, 2).ok()
+    } else {
+        s.parse().ok()
+    }
+}
+
+/// Extension code for a riscv-opcodes file name, e.g. `rv_m` -> `M`, `rv64_zba` -> `Zba`
+fn opcodes_code_from_stem(stem: &str) -> String {
+    let ext = stem
+        .strip_prefix("rv32_")
+        .or_else(|| stem.strip_prefix("rv64_"))
+        .or_else(|| stem.strip_prefix("rv128_"))
+        .or_else(|| stem.strip_prefix("rv_"))
+        .unwrap_or(stem);
+    let mut chars = ext.chars();
+    match chars.next() {
+        Some(c) => c.to_ascii_uppercase().to_string() + chars.as_str(),
+        None => stem.to_owned(),
+    }
+}
+
+// riscv-opcodes import
+impl RiscVSpec {
+    /// Loads instructions from a file in the riscv-opcodes format,
+    /// the extension code is derived from the file name (`rv_zicsr` -> `Zicsr`).
+    /// Returns warnings about the lines that were skipped
+    pub fn load_opcodes_file(&mut self, path: &std::path::Path) -> Result<Vec<String>, LoadError> {
+        let content = std::fs::read_to_string(path)
+            .map_err(|e| LoadError::Io(path.to_owned(), e.to_string()))?;
+        let stem = path
+            .file_stem()
+            .map(|s| s.to_string_lossy().into_owned())
+            .unwrap_or_default();
+        let skipped = self
+            .parse_opcodes(&content, &opcodes_code_from_stem(&stem))
+            .map_err(|(line, e)| LoadError::Located(path.to_owned(), Some(line), Box::new(e)))?;
+        Ok(skipped
+            .into_iter()
+            .map(|(line, w)| format!("{}:{}: {}", path.display(), line + 1, w))
+            .collect())
+    }
+
+    /// Returns warnings about the lines that were skipped
+    pub fn load_opcodes_string(
+        &mut self,
+        content: &str,
+        code: &str,
+    ) -> Result<Vec<String>, LoadError> {
+        let skipped = self.parse_opcodes(content, code).map_err(|(_, e)| e)?;
+        Ok(skipped
+            .into_iter()
+            .map(|(line, w)| format!("line {}: {}", line + 1, w))
+            .collect())
+    }
+
+    /// Errors are paired with the 0-based line they occurred on,
+    /// the spec is left as it was before when there is one.
+    /// Returns the 0-based lines that were skipped, with the reason
+    fn parse_opcodes(
+        &mut self,
+        content: &str,
+        code: &str,
+    ) -> Result<Vec<(usize, String)>, (usize, LoadError)> {
+        let formats_len = self.instruction_formats.len();
+        let instructions_len = self.instructions.len();
+        let mut skipped = Vec::new();
+        for (lineno, line) in content.lines().enumerate() {
+            let line = line.split('#').next().unwrap_or("").trim();
+            if line.is_empty() {
+                continue;
+            }
+            // pseudo-ops duplicate real instructions and imports refer to definitions
+            // in other files, which have to be loaded from those files
+            if line.starts_with('$') {
+                let mut tokens = line.split_whitespace();
+                let (directive, target) = (tokens.next().unwrap(), tokens.next().unwrap_or(""));
+                skipped.push((lineno, format!("skipped '{} {}'", directive, target)));
+                continue;
+            }
+            if let Err(e) = self.parse_opcodes_line(line, code) {
+                self.instruction_formats.truncate(formats_len);
+                self.instructions.truncate(instructions_len);
+                self.instruction_name_lookup
+                    .retain(|_, idx| *idx < instructions_len);
+                return Err((lineno, e));
+            }
+        }
+
+        self.loaded_names.push(format!("riscv-opcodes {}", code));
+        self.loaded_codes.push(code.to_owned());
+        self.loaded_specs.push("riscv-opcodes".to_owned());
+        Ok(skipped)
+    }
+
+    fn parse_opcodes_line(&mut self, line: &str, code: &str) -> Result<(), LoadError> {
+        let mut tokens = line.split_whitespace();
+        let iname = tokens.next().unwrap().to_ascii_lowercase();
+        let mut fmt_sig = Vec::new();
+        let mut fields = Vec::new();
+        let mut args: Vec<&str> = Vec::new();
+        let mut fixed = Vec::new();
+        let mut major = None;
+        let tokens: Vec<&str> = tokens.collect();
+        for &tok in tokens.iter() {
+            if let Some((range, value)) = tok.split_once('=') {
+                let bad_range = || LoadError::BadType(format!("{}: {}", iname, tok));
+                let (hi, lo) = match range.split_once("..") {
+                    Some((hi, lo)) => (hi, lo),
+                    None => (range, range),
+                };
+                let hi: i32 = hi.parse().map_err(|_| bad_range())?;
+                let lo: i32 = lo.parse().map_err(|_| bad_range())?;
+                if hi < lo {
+                    return Err(bad_range());
+                }
+                let fname = if hi == lo {
+                    format!("f{}", lo)
+                } else {
+                    format!("f{}_{}", hi, lo)
+                };
+                fmt_sig.push(if hi == lo {
+                    lo.to_string()
+                } else {
+                    format!("{}..{}", hi, lo)
+                });
+                fields.push(InstructionField {
+                    name: fname.clone(),
+                    vtype: FieldType::Value,
+                    length: hi - lo + 1,
+                    encoding: std::iter::once(BitRangeMap::new(hi - lo, 0, lo)).collect(),
+                });
+                if value != "ignore" {
+                    let value = parse_opcodes_int(value).ok_or_else(bad_range)?;
+                    if (hi, lo) == (6, 2) {
+                        major = Some(value);
+                    }
+                    fixed.push((fname, value));
+                }
+            } else {
+                let (_, fname, vtype, length, encoding) =
+                    OPCODES_ARGS.iter().find(|a| a.0 == tok).ok_or_else(|| {
+                        LoadError::UnknownOpcodesArgument(iname.clone(), tok.to_owned())
+                    })?;
+                if args.contains(fname) {
+                    continue;
+                }
+                args.push(fname);
+                // by the riscv-opcodes name, immediates of the same field name differ in encoding
+                fmt_sig.insert(args.len() - 1, tok.to_string());
+                fields.push(InstructionField {
+                    name: fname.to_string(),
+                    vtype: *vtype,
+                    length: *length,
+                    encoding: encoding
+                        .iter()
+                        .map(|(vl, vf, i)| BitRangeMap::new(*vl, *vf, *i))
+                        .collect(),
+                });
+            }
+        }
+
+        if let Some(order) = opcodes_assembler_order(&tokens, major) {
+            if order.len() != args.len() || !args.iter().all(|a| order.contains(a)) {
+                return Err(LoadError::UnmappedOpcodesArguments(
+                    iname,
+                    tokens
+                        .iter()
+                        .filter(|t| !t.contains('='))
+                        .map(|t| t.to_string())
+                        .collect(),
+                ));
+            }
+            args = order.to_vec();
+        }
+
+        let fmtname = format!("opcodes {}", fmt_sig.join(" "));
+        let format_idx = match self
+            .instruction_formats
+            .iter()
+            .position(|x| x.name == fmtname)
+        {
+            Some(i) => i,
+            None => {
+                let mut fmt = InstructionFormat::new(fmtname);
+                fmt.fields = fields.into_iter().collect();
+                fmt.ilen = fmt.calculate_last_encoded_bit_index() as usize + 1;
+                self.instruction_formats.push(fmt);
+                self.instruction_formats.len() - 1
+            }
+        };
+        let fmt = &self.instruction_formats[format_idx];
+        let field_idx = |name: &str| fmt.fields.iter().position(|f| f.name == name).unwrap();
+
+        let mut insn = InstructionDefinition::new(iname.clone());
+        insn.extension = code.to_owned();
+        insn.format_idx = format_idx;
+        insn.args = args.iter().map(|a| field_idx(a)).collect();
+        insn.fields = fixed.iter().map(|(f, v)| (field_idx(f), *v)).collect();
+        insn.check_length_encoding(fmt)
+            .map_err(|len| LoadError::BadLengthEncoding(iname.clone(), len, fmt.ilen))?;
+
+        if self.instruction_name_lookup.contains_key(&iname) {
+            return Err(LoadError::DuplicateInstruction(iname));
+        }
+        self.instruction_name_lookup
+            .insert(iname, self.instructions.len());
+        self.instructions.push(insn);
+        Ok(())
+    }
+}
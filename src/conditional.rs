@@ -0,0 +1,178 @@
+use crate::arch;
+use crate::parser::{Node, SourceError, SourceLocation, SymbolProvider};
+use std::collections::HashMap;
+
+/// Symbols known while reading the source top to bottom, used by the conditions of `.if`.
+/// Labels and constants whose value depends on labels are defined but have no value yet.
+pub struct Definitions<'a> {
+    spec: &'a arch::RiscVSpec,
+    symbols: HashMap<String, Option<u64>>,
+}
+
+impl<'a> Definitions<'a> {
+    pub fn new(spec: &'a arch::RiscVSpec) -> Self {
+        Definitions {
+            spec,
+            symbols: HashMap::new(),
+        }
+    }
+
+    pub fn define(&mut self, name: &str, value: Option<u64>) {
+        self.symbols.insert(name.to_owned(), value);
+    }
+
+    /// Takes note of the labels and constants defined by a top-level element
+    pub fn record(&mut self, node: &Node) {
+        match node {
+            Node::Label(name) => self.define(name, None),
+            Node::Instruction(iname, args) if is_constant_definition(iname) => {
+                if let [Node::Argument(box Node::Identifier(name)), Node::Argument(box value)] =
+                    args.as_slice()
+                {
+                    let value = self.value_of(value).ok();
+                    self.define(name, value);
+                }
+            }
+            _ => {}
+        }
+    }
+
+    /// Value of a constant expression, or the symbol keeping it from being constant
+    pub fn value_of(&self, expr: &Node) -> Result<u64, SourceError> {
+        if contains_pc(expr) {
+            return Err(SourceError::NotConstant(None));
+        }
+        match expr.emitter_simplify(self, 0) {
+            Ok((Node::Integer(v), _)) => Ok(v),
+            Ok((unresolved, _)) => Err(SourceError::NotConstant(
+                unresolved.identifiers().first().map(|n| n.to_string()),
+            )),
+            Err(e) => Err(SourceError::InvalidCondition(e)),
+        }
+    }
+
+    fn is_symbol(&self, name: &str) -> bool {
+        self.symbols.contains_key(name) || self.spec.get_const(name).is_some()
+    }
+}
+
+impl SymbolProvider for Definitions<'_> {
+    fn get_symbol(&self, name: &str) -> Option<u64> {
+        match self.symbols.get(name) {
+            Some(value) => *value,
+            None => self.spec.get_const(name).or_else(|| {
+                crate::parser::binary_literal(name, |num| self.symbols.contains_key(num))
+            }),
+        }
+    }
+
+    fn is_defined(&self, name: &str) -> Option<bool> {
+        Some(self.is_symbol(name))
+    }
+}
+
+fn is_constant_definition(name: &str) -> bool {
+    matches!(name, ".equ" | ".define" | ".set")
+}
+
+fn contains_pc(node: &Node) -> bool {
+    matches!(node, Node::PcValue) || node.children().into_iter().any(contains_pc)
+}
+
+pub fn is_conditional(name: &str) -> bool {
+    matches!(
+        name,
+        ".if" | ".ifdef" | ".ifndef" | ".elif" | ".else" | ".endif"
+    )
+}
+
+struct Block {
+    /// Where the `.if` is, for reporting a missing `.endif`
+    location: SourceLocation,
+    /// Whether the code around the block is assembled
+    outer_active: bool,
+    /// Whether one of the branches so far was taken
+    taken: bool,
+    active: bool,
+    seen_else: bool,
+}
+
+/// Nesting of the conditional blocks in one sequence of elements (a file or a macro body)
+#[derive(Default)]
+pub struct Conditionals {
+    blocks: Vec<Block>,
+}
+
+impl Conditionals {
+    /// Whether elements at this point are assembled
+    pub fn active(&self) -> bool {
+        self.blocks.last().is_none_or(|b| b.active)
+    }
+
+    /// Updates the blocks for the conditional directive `name`,
+    /// conditions are only evaluated when their branch could be taken
+    pub fn process(
+        &mut self,
+        name: &str,
+        args: &[Node],
+        loc: &SourceLocation,
+        defs: &Definitions,
+    ) -> Result<(), SourceError> {
+        match name {
+            ".if" | ".ifdef" | ".ifndef" => {
+                let outer_active = self.active();
+                let taken = outer_active && condition(name, args, defs)?;
+                self.blocks.push(Block {
+                    location: loc.clone(),
+                    outer_active,
+                    taken,
+                    active: taken,
+                    seen_else: false,
+                });
+            }
+            ".elif" | ".else" => {
+                let block = self
+                    .blocks
+                    .last_mut()
+                    .ok_or_else(|| SourceError::UnmatchedConditional(name.to_owned()))?;
+                if block.seen_else {
+                    return Err(SourceError::ConditionAfterElse(name.to_owned()));
+                }
+                block.active = block.outer_active
+                    && !block.taken
+                    && (name == ".else" || condition(name, args, defs)?);
+                block.taken |= block.active;
+                block.seen_else = name == ".else";
+            }
+            _ => {
+                self.blocks
+                    .pop()
+                    .ok_or_else(|| SourceError::UnmatchedConditional(name.to_owned()))?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Checks that every block was closed with `.endif`
+    pub fn finish(self) -> Result<(), SourceError> {
+        match self.blocks.last() {
+            Some(block) => Err(SourceError::UnterminatedConditional.located(&block.location)),
+            None => Ok(()),
+        }
+    }
+}
+
+fn condition(name: &str, args: &[Node], defs: &Definitions) -> Result<bool, SourceError> {
+    let arg = match args {
+        [Node::Argument(box arg)] => arg,
+        _ => return Err(SourceError::InvalidConditionArguments(name.to_owned())),
+    };
+    match (name, arg) {
+        (".ifdef", Node::Identifier(symbol)) => Ok(defs.is_symbol(symbol)),
+        (".ifndef", Node::Identifier(symbol)) => Ok(!defs.is_symbol(symbol)),
+        (".ifdef", _) | (".ifndef", _) => {
+            Err(SourceError::InvalidConditionArguments(name.to_owned()))
+        }
+        _ => defs.value_of(arg).map(|v| v != 0),
+    }
+}
@@ -1,7 +1,12 @@
 use crate::arch;
-use crate::parser::Node;
+use crate::emit::section::{
+    flat_image, place_sections, section_overlaps, stored_sections, LayoutOptions, Section,
+    StoredBy, MAX_SECTION_SIZE,
+};
+use crate::parser::{EvalError, Node, SourceLocation, SymbolProvider};
 use smallvec::SmallVec;
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
+use std::convert::TryFrom;
 
 #[derive(Clone, Debug)]
 pub enum EmitError {
@@ -12,73 +17,736 @@ pub enum EmitError {
     InvalidEncoding(String),
     DuplicateLabel(String),
     DuplicateConstant(String),
+    /// Instruction or directive name and the error from evaluating one of its operands
+    InvalidExpression(String, EvalError),
+    /// Every reference to a symbol that was never defined
+    UndefinedSymbol(Vec<UndefinedReference>),
+    /// Constant whose value depends on itself
+    CircularDefinition(String),
+    /// Directive and the symbol it needs before that symbol is defined
+    ForwardReference(String, String),
+    /// Offset and length of an `.incbin` range that doesn't fit in the file of the given size
+    IncbinRange(u64, u64, usize),
+    /// Directive or instruction storing bytes in a section that only reserves space
+    NoBitsData(String, String),
+    /// `.org` address before the start of the current section, and that section
+    OrgBeforeSection(u64, String),
+    /// Address of a byte stored again, and where it was stored before
+    Overlap(u64, Option<SourceLocation>),
+    /// Section storing a byte at the given address, where the other section stores one too
+    SectionOverlap(String, String, u64),
+    /// Section storing bytes at an address before the base address of the output
+    SectionBeforeBase(String, u64, u64),
+    /// Section start addresses that keep changing with the sizes of the sections
+    UnsettledLayout,
+    /// Alignment directive and its alignment that isn't a power of two
+    InvalidAlignment(String, u64),
+    /// `.p2align`/`.align` and its power of two that doesn't fit in 64 bits
+    AlignmentPowerOutOfRange(String, u64),
+    /// Alignment directive and its alignment that is larger than a section can be
+    AlignmentTooLarge(String, u64),
+    /// Section and the size it would grow to beyond the largest one allowed
+    SectionTooLarge(String, u64),
+    /// Directive reserving space and its negative size
+    NegativeSize(String, i64),
+    /// Error in the top-level element at the given location
+    Located(SourceLocation, Box<EmitError>),
 }
 
+/// A use of an undefined symbol, with the closest known name if there is one
+#[derive(Clone, Debug)]
+pub struct UndefinedReference {
+    pub name: String,
+    pub location: Option<SourceLocation>,
+    pub suggestion: Option<String>,
+}
+
+impl std::fmt::Display for UndefinedReference {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if let Some(loc) = &self.location {
+            write!(f, "{}: ", loc)?;
+        }
+        write!(f, "undefined symbol '{}'", self.name)?;
+        if let Some(suggestion) = &self.suggestion {
+            write!(f, ", did you mean '{}'?", suggestion)?;
+        }
+        Ok(())
+    }
+}
+
+impl EmitError {
+    fn located(self, loc: Option<&SourceLocation>) -> Self {
+        match (self, loc) {
+            (EmitError::Located(l, e), _) => EmitError::Located(l, e),
+            (e, Some(loc)) => EmitError::Located(loc.clone(), Box::new(e)),
+            (e, None) => e,
+        }
+    }
+}
+
+impl std::fmt::Display for EmitError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            EmitError::UnexpectedNodeType(node) => write!(f, "unexpected node {}", node),
+            EmitError::InvalidInstruction(iname) => write!(f, "unknown instruction '{}'", iname),
+            EmitError::InvalidArgumentCount(iname) => {
+                write!(f, "wrong number of arguments for '{}'", iname)
+            }
+            EmitError::InvalidArgumentType(iname, i) => {
+                write!(f, "invalid type of argument {} of '{}'", i + 1, iname)
+            }
+            EmitError::InvalidEncoding(iname) => write!(f, "'{}' can't be encoded", iname),
+            EmitError::DuplicateLabel(name) => write!(f, "duplicate label '{}'", name),
+            EmitError::DuplicateConstant(name) => write!(f, "duplicate constant '{}'", name),
+            EmitError::InvalidExpression(iname, e) => write!(f, "'{}': {}", iname, e),
+            EmitError::UndefinedSymbol(refs) => {
+                let lines: Vec<String> = refs.iter().map(|r| r.to_string()).collect();
+                write!(f, "{}", lines.join("\n"))
+            }
+            EmitError::CircularDefinition(name) => {
+                write!(f, "constant '{}' is defined in terms of itself", name)
+            }
+            EmitError::ForwardReference(iname, name) => {
+                write!(
+                    f,
+                    "'{}' needs the value of '{}' before it is defined",
+                    iname, name
+                )
+            }
+            EmitError::IncbinRange(offset, length, size) => write!(
+                f,
+                "'.incbin' of {} bytes at offset {} is past the end of the {}-byte file",
+                length, offset, size
+            ),
+            EmitError::NoBitsData(iname, section) => write!(
+                f,
+                "'{}' can't store bytes in '{}', which only reserves space",
+                iname, section
+            ),
+            EmitError::OrgBeforeSection(address, section) => write!(
+                f,
+                "'.org' address {:#x} is before the start of section '{}'",
+                address, section
+            ),
+            EmitError::Overlap(address, Some(loc)) => write!(
+                f,
+                "overwrites the byte at {:#x} already stored at {}",
+                address, loc
+            ),
+            EmitError::Overlap(address, None) => {
+                write!(f, "overwrites the byte at {:#x} already stored", address)
+            }
+            EmitError::SectionOverlap(section, other, address) => write!(
+                f,
+                "section '{}' overwrites the byte at {:#x} stored by section '{}'",
+                section, address, other
+            ),
+            EmitError::SectionBeforeBase(section, start, base) => write!(
+                f,
+                "section '{}' starts at {:#x}, before the base address {:#x}",
+                section, start, base
+            ),
+            EmitError::UnsettledLayout => write!(f, "section addresses don't settle"),
+            EmitError::InvalidAlignment(iname, align) => {
+                write!(f, "'{}' alignment {} is not a power of two", iname, align)
+            }
+            EmitError::AlignmentPowerOutOfRange(iname, power) => {
+                write!(f, "'{}' power {} out of range 0..=63", iname, power)
+            }
+            EmitError::AlignmentTooLarge(iname, align) => write!(
+                f,
+                "'{}' alignment {:#x} is larger than the maximum of {:#x}",
+                iname, align, MAX_SECTION_SIZE
+            ),
+            EmitError::SectionTooLarge(section, size) => write!(
+                f,
+                "section '{}' would grow to {:#x} bytes, more than the maximum of {:#x}",
+                section, size, MAX_SECTION_SIZE
+            ),
+            EmitError::NegativeSize(iname, size) => {
+                write!(f, "'{}' size {} is negative", iname, size)
+            }
+            EmitError::Located(loc, e) => write!(f, "{}: {}", loc, e),
+        }
+    }
+}
+
+impl std::error::Error for EmitError {}
+
+/// How many times the source may be emitted for the section addresses to settle
+const MAX_LAYOUT_PASSES: usize = 8;
+
 pub fn emit_flat_binary(spec: &arch::RiscVSpec, ast: &Node) -> Result<Vec<u8>, EmitError> {
+    emit_flat_binary_with_layout(spec, ast, &LayoutOptions::default()).map(|(bin, _)| bin)
+}
+
+/// The flat binary and the warnings about it
+pub fn emit_flat_binary_with_layout(
+    spec: &arch::RiscVSpec,
+    ast: &Node,
+    options: &LayoutOptions,
+) -> Result<(Vec<u8>, Vec<EmitError>), EmitError> {
+    // where a section starts depends on the size of the ones before it, so the source is emitted
+    // again with the new start addresses until they stop changing
+    let base = options.base_address;
+    let mut starts = HashMap::new();
+    for _ in 0..MAX_LAYOUT_PASSES {
+        let state = emit_pass(spec, ast, starts, options)?;
+        let placed = place_sections(&state.sections, options);
+        if state
+            .sections
+            .iter()
+            .enumerate()
+            .all(|(idx, s)| state.start_of(idx) == placed[&s.name])
+        {
+            if let Some(s) = stored_sections(&state.sections).find(|s| placed[&s.name] < base) {
+                return Err(EmitError::SectionBeforeBase(
+                    s.name.clone(),
+                    placed[&s.name],
+                    base,
+                ));
+            }
+            // sections placed over each other with explicit start addresses
+            let mut warnings = state.warnings;
+            for (earlier, later, address, loc) in section_overlaps(&state.sections, &placed) {
+                let error =
+                    EmitError::SectionOverlap(later, earlier, address).located(loc.as_ref());
+                if !options.allow_overlap {
+                    return Err(error);
+                }
+                warnings.push(error);
+            }
+            return Ok((flat_image(&state.sections, &placed, base), warnings));
+        }
+        starts = placed;
+    }
+    Err(EmitError::UnsettledLayout)
+}
+
+fn emit_pass(
+    spec: &arch::RiscVSpec,
+    ast: &Node,
+    starts: HashMap<String, u64>,
+    options: &LayoutOptions,
+) -> Result<BinaryEmitState, EmitError> {
     let mut state = BinaryEmitState {
-        out_buf: Vec::new(),
-        out_pos: 0,
+        sections: vec![Section::new(".text")],
+        current: 0,
+        starts,
+        base: options.base_address,
+        allow_overlap: options.allow_overlap,
+        warnings: Vec::new(),
+        stores: 0,
         deferred: Vec::new(),
+        location: None,
+        element: 0,
         label_set: HashMap::new(),
-        local_label_set: HashMap::new(),
         const_set: HashMap::new(),
+        set_names: HashSet::new(),
+        pending_consts: Vec::new(),
+        pcrel_hi_set: HashMap::new(),
+        label_order: Vec::new(),
+        finished: false,
     };
-    emit_binary_recurse(spec, &mut state, ast).map(move |_| state.out_buf)
+    emit_binary_recurse(spec, &mut state, ast).map(move |_| state)
 }
 
 #[derive(Debug)]
 struct BinaryEmitState {
-    out_buf: Vec<u8>,
-    out_pos: usize,
-    deferred: Vec<(usize, Node)>,
+    /// Sections in order of appearance, starting with `.text`
+    sections: Vec<Section>,
+    /// Index of the section being emitted into
+    current: usize,
+    /// Start addresses of the sections in this pass, the base address for ones not placed yet
+    starts: HashMap<String, u64>,
+    /// Address the start of the output is loaded at
+    base: u64,
+    /// Whether bytes stored twice are reported as warnings instead of errors
+    allow_overlap: bool,
+    warnings: Vec<EmitError>,
+    /// Number of byte ranges stored so far, which identifies the next one
+    stores: usize,
+    deferred: Vec<DeferredInstruction>,
+    /// Location of the top-level element being emitted
+    location: Option<SourceLocation>,
+    /// Index of the top-level element being emitted, which orders elements across files
+    element: usize,
+    /// All labels, local ones qualified with their parent (`main.loop`)
     label_set: HashMap<String, u64>,
-    local_label_set: HashMap<String, u64>,
     const_set: HashMap<String, u64>,
+    /// Constants defined with `.set`/`=`, which can be assigned again
+    set_names: HashSet<String>,
+    /// `.equ` constants waiting for symbols: name, value, address, source location and index
+    /// of the element
+    pending_consts: Vec<(String, Node, u64, Option<SourceLocation>, usize)>,
+    /// Instruction addresses mapped to `sym - pc` of their `%pcrel_hi(sym)` operand
+    pcrel_hi_set: HashMap<u64, u64>,
+    /// Global labels and their sections in order of definition,
+    /// delimiting the regions measured by `sizeof`
+    label_order: Vec<(String, usize)>,
+    /// Set once the whole source was processed, so that missing symbols are known to be undefined
+    finished: bool,
+}
+
+/// An instruction waiting for symbols, and the bytes reserved for it
+#[derive(Debug)]
+struct DeferredInstruction {
+    section: usize,
+    /// Offsets of the reserved bytes in the section
+    pos: usize,
+    end: usize,
+    /// Number of the storing that reserved the bytes
+    store: usize,
+    /// Phase of the section at the instruction
+    phase: u64,
+    location: Option<SourceLocation>,
+    element: usize,
+    insn: Node,
+}
+
+struct EmitSymbols<'a> {
+    state: &'a BinaryEmitState,
+    spec: &'a arch::RiscVSpec,
+}
+
+impl SymbolProvider for EmitSymbols<'_> {
+    fn get_symbol(&self, name: &str) -> Option<u64> {
+        self.state.find_const(name, self.spec)
+    }
+
+    fn get_pcrel_hi(&self, address: u64) -> Option<u64> {
+        self.state.pcrel_hi_set.get(&address).copied()
+    }
+
+    fn get_size(&self, name: &str) -> Option<u64> {
+        let state = self.state;
+        let start = *state.label_set.get(name)?;
+        let idx = state.label_order.iter().position(|l| l.0 == name)?;
+        let section = state.label_order[idx].1;
+        // the region ends at the next label in the same section, or the end of the section
+        let next = state.label_order[idx + 1..].iter().find(|l| l.1 == section);
+        let end = match next {
+            Some((next, _)) => state.label_set[next],
+            None if state.finished => {
+                state.start_of(section) + state.sections[section].size() as u64
+            }
+            None => return None,
+        };
+        Some(end.wrapping_sub(start))
+    }
+
+    fn is_defined(&self, name: &str) -> Option<bool> {
+        if self.get_symbol(name).is_some() {
+            Some(true)
+        } else if self.state.finished {
+            Some(false)
+        } else {
+            None
+        }
+    }
 }
 
 impl BinaryEmitState {
-    fn accomodate_bytes(&mut self, byte_count: usize) -> &mut [u8] {
-        let start_pos = self.out_pos;
-        let end_pos = start_pos + byte_count;
-        if self.out_buf.len() < end_pos {
-            self.out_buf.resize(end_pos, 0);
+    /// The next `byte_count` bytes of the current section, which must not have been stored before
+    fn accomodate_bytes(&mut self, byte_count: usize) -> Result<&mut [u8], EmitError> {
+        let start_pos = self.section().pos;
+        let end_pos = start_pos
+            .checked_add(byte_count)
+            .filter(|end| *end as u64 <= MAX_SECTION_SIZE)
+            .ok_or_else(|| {
+                let size = (start_pos as u64).saturating_add(byte_count as u64);
+                EmitError::SectionTooLarge(self.section().name.clone(), size)
+            })?;
+        if byte_count > 0 {
+            let by = StoredBy {
+                id: self.stores,
+                loc: self.location.clone(),
+            };
+            self.stores += 1;
+            if let Some((pos, earlier)) =
+                self.sections[self.current].record_stored(start_pos, end_pos, by)
+            {
+                let section = self.section();
+                let error =
+                    EmitError::Overlap(self.address_at(self.current, pos, section.phase), earlier);
+                if !self.allow_overlap {
+                    return Err(error);
+                }
+                self.warnings.push(error.located(self.location.as_ref()));
+            }
+        }
+        let section = &mut self.sections[self.current];
+        section.extend_to(end_pos);
+        section.pos = end_pos;
+        if section.nobits {
+            // nothing is stored, the bytes only take up addresses
+            return Ok(&mut []);
         }
-        self.out_pos = end_pos;
-        &mut self.out_buf[start_pos..end_pos]
+        Ok(&mut section.data[start_pos..end_pos])
+    }
+
+    fn section(&self) -> &Section {
+        &self.sections[self.current]
+    }
+
+    fn start_of(&self, section: usize) -> u64 {
+        let name = &self.sections[section].name;
+        self.starts.get(name).copied().unwrap_or(self.base)
+    }
+
+    /// Address the code at `pos` in `section` runs at
+    fn address_at(&self, section: usize, pos: usize, phase: u64) -> u64 {
+        (self.start_of(section) + pos as u64).wrapping_add(phase)
+    }
+
+    /// Address of the location counter
+    fn address(&self) -> u64 {
+        self.address_at(self.current, self.section().pos, self.section().phase)
+    }
+
+    fn switch_section(&mut self, name: &str) {
+        self.current = match self.sections.iter().position(|s| s.name == name) {
+            Some(idx) => idx,
+            None => {
+                self.sections.push(Section::new(name));
+                self.sections.len() - 1
+            }
+        };
+    }
+
+    /// Pads the current section with `count` bytes of `fill`,
+    /// by default NOP instructions in code sections and zeroes elsewhere
+    fn pad(
+        &mut self,
+        spec: &arch::RiscVSpec,
+        iname: &str,
+        count: usize,
+        fill: Option<u8>,
+    ) -> Result<(), EmitError> {
+        let pos = self.section().pos;
+        let nops = self.section().code && !self.section().nobits;
+        match fill {
+            Some(fill) => {
+                if fill != 0 {
+                    self.check_stored(iname)?;
+                }
+                self.accomodate_bytes(count)?.fill(fill);
+            }
+            None if nops => fill_nops(spec, self.accomodate_bytes(count)?, pos),
+            None => self.accomodate_bytes(count)?.fill(0),
+        }
+        Ok(())
+    }
+
+    /// Errors for directives and instructions storing bytes in a section like `.bss`
+    fn check_stored(&self, iname: &str) -> Result<(), EmitError> {
+        if self.section().nobits {
+            return Err(EmitError::NoBitsData(
+                iname.to_owned(),
+                self.section().name.clone(),
+            ));
+        }
+        Ok(())
     }
 
     fn find_const(&self, key: &str, spec: &arch::RiscVSpec) -> Option<u64> {
         self.label_set
             .get(key)
-            .or_else(|| self.local_label_set.get(key))
             .or_else(|| self.const_set.get(key))
             .copied()
             .or_else(|| spec.get_const(key))
     }
+
+    fn is_const_defined(&self, name: &str) -> bool {
+        self.const_set.contains_key(name) || self.pending_consts.iter().any(|c| c.0 == name)
+    }
+
+    fn symbols<'a>(&'a self, spec: &'a arch::RiscVSpec) -> EmitSymbols<'a> {
+        EmitSymbols { state: self, spec }
+    }
+}
+
+/// Encoding of instruction `iname` with all its arguments 0, if the spec has it
+fn encode_zero_args(spec: &arch::RiscVSpec, iname: &str) -> Option<Vec<u8>> {
+    let insn = spec.get_instruction_by_name(iname)?;
+    let mut bytes = vec![0; insn.get_format(spec).ilen.div_ceil(8)];
+    insn.encode_into(&mut bytes, spec, &vec![0; insn.args.len()])
+        .ok()?;
+    Some(bytes)
+}
+
+/// Fills the padding at offset `pos` of a section with the canonical NOP (`addi x0, x0, 0`),
+/// `c.nop` for 2-byte gaps if the spec has it, and zeroes where neither fits
+fn fill_nops(spec: &arch::RiscVSpec, bytes: &mut [u8], pos: usize) {
+    let nops: Vec<Vec<u8>> = ["addi", "c.nop"]
+        .iter()
+        .filter_map(|iname| encode_zero_args(spec, iname))
+        .collect();
+    let mut i = 0;
+    while i < bytes.len() {
+        let fitting = nops
+            .iter()
+            .find(|nop| (pos + i).is_multiple_of(nop.len()) && i + nop.len() <= bytes.len());
+        match fitting {
+            Some(nop) => {
+                bytes[i..i + nop.len()].copy_from_slice(nop);
+                i += nop.len();
+            }
+            None => {
+                bytes[i] = 0;
+                i += 1;
+            }
+        }
+    }
+}
+
+/// Value of the argument at `index`, which the directive `iname` needs right away
+fn immediate_value(
+    spec: &arch::RiscVSpec,
+    state: &BinaryEmitState,
+    iname: &str,
+    args: &[Node],
+    index: usize,
+) -> Result<u64, EmitError> {
+    match args[index]
+        .emitter_simplify(&state.symbols(spec), state.address())
+        .map_err(|e| EmitError::InvalidExpression(iname.to_owned(), e))?
+    {
+        (Node::Argument(box Node::Integer(val)), _) => Ok(val),
+        (value, false) => Err(EmitError::ForwardReference(
+            iname.to_owned(),
+            value.identifiers().first().map_or("", |n| n).to_owned(),
+        )),
+        (_, true) => Err(EmitError::InvalidArgumentType(iname.to_owned(), index)),
+    }
+}
+
+/// Remembers `sym - pc` of `%pcrel_hi(sym)` operands, for `%pcrel_lo` references to this instruction
+fn record_pcrel_hi(spec: &arch::RiscVSpec, state: &mut BinaryEmitState, node: &Node, pc: u64) {
+    if let Node::PcrelHi(box sym) = node {
+        // evaluation errors are reported once the whole instruction gets simplified
+        if let Ok((Node::Integer(v), _)) = sym.emitter_simplify(&state.symbols(spec), pc) {
+            state.pcrel_hi_set.insert(pc, v.wrapping_sub(pc));
+        }
+    }
+    for child in node.children() {
+        record_pcrel_hi(spec, state, child, pc);
+    }
+}
+
+/// Evaluates the `.equ` constants whose values became known, returns whether there were any
+fn resolve_pending_consts(
+    spec: &arch::RiscVSpec,
+    state: &mut BinaryEmitState,
+) -> Result<bool, EmitError> {
+    let mut progress = false;
+    // a resolved constant can be what another one was waiting for
+    loop {
+        let mut resolved = false;
+        for (name, value, pc, loc, element) in std::mem::take(&mut state.pending_consts).into_iter()
+        {
+            let simp = value
+                .emitter_simplify(&state.symbols(spec), pc)
+                .map_err(|e| EmitError::InvalidExpression(".equ".to_owned(), e))
+                .map_err(|e| e.located(loc.as_ref()))?;
+            match simp {
+                (Node::Integer(v), _) => {
+                    state.const_set.insert(name, v);
+                    resolved = true;
+                }
+                (_, true) => {
+                    return Err(
+                        EmitError::InvalidArgumentType(".equ".to_owned(), 1).located(loc.as_ref())
+                    );
+                }
+                (_, false) => state.pending_consts.push((name, value, pc, loc, element)),
+            }
+        }
+        if !resolved {
+            return Ok(progress);
+        }
+        progress = true;
+    }
 }
 
 fn emit_deferred(spec: &arch::RiscVSpec, state: &mut BinaryEmitState) -> Result<(), EmitError> {
-    let mut to_remove = Vec::new();
-    let mut to_emit = Vec::new();
-    for (i, (pos, insn)) in state.deferred.iter().enumerate() {
-        let pc = *pos as u64;
-        let simp = insn.emitter_simplify(&|cname| state.find_const(cname, spec), pc);
-        if !simp.1 {
-            continue;
+    // emitting an instruction can resolve the %pcrel_lo of another one, so repeat until no progress
+    loop {
+        let consts_resolved = resolve_pending_consts(spec, state)?;
+        let mut to_emit = Vec::new();
+        for mut deferred in std::mem::take(&mut state.deferred).into_iter() {
+            let pc = state.address_at(deferred.section, deferred.pos, deferred.phase);
+            record_pcrel_hi(spec, state, &deferred.insn, pc);
+            let simp = deferred
+                .insn
+                .emitter_simplify(&state.symbols(spec), pc)
+                .map_err(|e| match &deferred.insn {
+                    Node::Instruction(iname, _) => EmitError::InvalidExpression(iname.clone(), e),
+                    _ => EmitError::UnexpectedNodeType(format!("{:?}", deferred.insn)),
+                })
+                .map_err(|e| e.located(deferred.location.as_ref()))?;
+            if simp.1 {
+                deferred.insn = simp.0;
+                to_emit.push(deferred);
+            } else {
+                state.deferred.push(deferred);
+            }
+        }
+        if to_emit.is_empty() && !consts_resolved {
+            return Ok(());
+        }
+        for deferred in to_emit.into_iter() {
+            let (pos, end) = (deferred.pos, deferred.end);
+            let section = &mut state.sections[deferred.section];
+            // the bytes were reserved when the instruction was deferred, the parts of them that
+            // something stored later overwrote (with overlaps allowed) are kept
+            let starts = section.stored_between(pos, end);
+            if !starts
+                .iter()
+                .any(|s| section.stored[s].1.id == deferred.store)
+            {
+                continue;
+            }
+            let mut later = Vec::new();
+            for s in starts {
+                let (e, by) = section.stored.remove(&s).unwrap();
+                if by.id != deferred.store {
+                    later.push((s, e, by));
+                }
+            }
+            let kept = section.data[pos..end].to_vec();
+
+            let saved = state.current;
+            state.current = deferred.section;
+            let section = &mut state.sections[deferred.section];
+            let saved_pos = std::mem::replace(&mut section.pos, pos);
+            let saved_phase = std::mem::replace(&mut section.phase, deferred.phase);
+            state.location = deferred.location;
+            emit_binary_recurse(spec, state, &deferred.insn)
+                .map_err(|e| e.located(state.location.as_ref()))?;
+            let section = &mut state.sections[deferred.section];
+            section.pos = saved_pos;
+            section.phase = saved_phase;
+            state.current = saved;
+            for (s, e, by) in later {
+                let (from, to) = (s.max(pos), e.min(end));
+                section.data[from..to].copy_from_slice(&kept[from - pos..to - pos]);
+                section.record_stored(s, e, by);
+            }
+        }
+        state.location = None;
+    }
+}
+
+/// Optimal string alignment distance, case-insensitive, so that swapped letters count as one edit
+fn edit_distance(a: &str, b: &str) -> usize {
+    let a: Vec<char> = a.to_lowercase().chars().collect();
+    let b: Vec<char> = b.to_lowercase().chars().collect();
+    let mut d = vec![vec![0; b.len() + 1]; a.len() + 1];
+    for (i, row) in d.iter_mut().enumerate() {
+        row[0] = i;
+    }
+    for (j, cell) in d[0].iter_mut().enumerate() {
+        *cell = j;
+    }
+    for i in 1..=a.len() {
+        for j in 1..=b.len() {
+            let cost = (a[i - 1] != b[j - 1]) as usize;
+            d[i][j] = (d[i - 1][j] + 1)
+                .min(d[i][j - 1] + 1)
+                .min(d[i - 1][j - 1] + cost);
+            if i > 1 && j > 1 && a[i - 1] == b[j - 2] && a[i - 2] == b[j - 1] {
+                d[i][j] = d[i][j].min(d[i - 2][j - 2] + 1);
+            }
         }
-        to_emit.push((*pos, simp.0));
-        to_remove.push(i);
     }
-    for i in to_remove.iter().rev() {
-        state.deferred.swap_remove(*i);
+    d[a.len()][b.len()]
+}
+
+/// The known label or constant closest to `name`, if it's close enough to be a typo
+fn suggest_symbol(spec: &arch::RiscVSpec, state: &BinaryEmitState, name: &str) -> Option<String> {
+    state
+        .label_set
+        .keys()
+        .chain(state.const_set.keys())
+        .chain(spec.get_all_consts().keys())
+        // unique names of numeric labels aren't something to suggest
+        .filter(|known| !known.contains('#'))
+        .map(|known| (edit_distance(name, known), known))
+        .filter(|(dist, _)| *dist <= (name.len() / 3).max(1))
+        .min()
+        .map(|(_, known)| known.to_owned())
+}
+
+/// Error for the instructions that are still deferred once all the source was processed
+fn unresolved_error(spec: &arch::RiscVSpec, state: &BinaryEmitState) -> EmitError {
+    // symbols that pending constants are still waiting for
+    let mut waits_for: HashMap<&str, Vec<String>> = HashMap::new();
+    for (name, value, pc, _, _) in state.pending_consts.iter() {
+        let unresolved = value
+            .emitter_simplify(&state.symbols(spec), *pc)
+            .map_or_else(|_| value.clone(), |s| s.0);
+        let names = unresolved
+            .identifiers()
+            .iter()
+            .map(|n| (*n).to_owned())
+            .collect();
+        waits_for.insert(name, names);
     }
-    for (pos, insn) in to_emit.into_iter() {
-        let saved_pos = state.out_pos;
-        state.out_pos = pos;
-        emit_binary_recurse(&spec, state, &insn)?;
-        state.out_pos = saved_pos;
+    for (name, _, _, loc, _) in state.pending_consts.iter() {
+        let mut seen = HashSet::new();
+        let mut stack = waits_for[name.as_str()].clone();
+        while let Some(dep) = stack.pop() {
+            if dep == *name {
+                return EmitError::CircularDefinition(name.to_owned()).located(loc.as_ref());
+            }
+            if seen.insert(dep.clone()) {
+                stack.extend(waits_for.get(dep.as_str()).into_iter().flatten().cloned());
+            }
+        }
+    }
+    // pending constants are reported through the symbols they are waiting for
+    let pending_refs = state
+        .pending_consts
+        .iter()
+        .map(|(_, value, pc, loc, element)| (*pc, loc, *element, value));
+    let insn_refs = state.deferred.iter().map(|d| {
+        (
+            state.address_at(d.section, d.pos, d.phase),
+            &d.location,
+            d.element,
+            &d.insn,
+        )
+    });
+
+    let mut refs = Vec::new();
+    for (pc, loc, element, insn) in pending_refs.chain(insn_refs) {
+        let unresolved = insn
+            .emitter_simplify(&state.symbols(spec), pc)
+            .map_or_else(|_| insn.clone(), |s| s.0);
+        let names = unresolved.identifiers();
+        if names.is_empty() {
+            // e.g. a %pcrel_lo without a matching %pcrel_hi
+            return EmitError::UnexpectedNodeType(format!("{:?}", insn)).located(loc.as_ref());
+        }
+        for name in names.into_iter().filter(|n| !waits_for.contains_key(n)) {
+            // numeric label references with no definition in their direction, see `resolve_labels`
+            let shown = match name.split_once('#') {
+                Some((num, "0")) => format!("{}b", num),
+                Some((num, _)) => format!("{}f", num),
+                None => name.to_owned(),
+            };
+            let reference = UndefinedReference {
+                name: shown,
+                location: loc.clone(),
+                suggestion: suggest_symbol(spec, state, name),
+            };
+            refs.push((element, reference));
+        }
     }
-    Ok(())
+    // in source order, across included files and sections
+    refs.sort_by_key(|r| r.0);
+    EmitError::UndefinedSymbol(refs.into_iter().map(|r| r.1).collect())
 }
 
 fn emit_binary_recurse(
@@ -88,92 +756,256 @@ fn emit_binary_recurse(
 ) -> Result<(), EmitError> {
     use Node::*;
 
-    let ialign_bytes = (spec.get_const("IALIGN").unwrap_or(32) as usize + 7) / 8;
-    let max_ilen_bytes = (spec.get_const("ILEN").unwrap_or(32) as usize + 7) / 8;
+    let ialign_bytes = (spec.get_const("IALIGN").unwrap_or(32) as usize).div_ceil(8);
+    let max_ilen_bytes = (spec.get_const("ILEN").unwrap_or(32) as usize).div_ceil(8);
 
     match node {
         Root(nodes) => {
-            for node in nodes.iter() {
+            for (element, node) in nodes.iter().enumerate() {
+                state.element = element;
                 emit_binary_recurse(spec, state, node)?;
             }
+            state.finished = true;
             emit_deferred(spec, state)?;
-            if let Some(defnode) = state.deferred.first() {
-                return Err(EmitError::UnexpectedNodeType(format!("{:?}", defnode)));
+            if !state.deferred.is_empty() {
+                return Err(unresolved_error(spec, state));
             }
             Ok(())
         }
+        Located(loc, box inner) => {
+            let outer = state.location.replace(loc.clone());
+            let res = emit_binary_recurse(spec, state, inner).map_err(|e| e.located(Some(loc)));
+            state.location = outer;
+            res
+        }
         Label(lname) => {
-            if lname.starts_with('.') {
-                // local label
-                if state
-                    .local_label_set
-                    .insert(lname.to_owned(), state.out_pos as u64)
-                    .is_some()
-                {
-                    return Err(EmitError::DuplicateLabel(lname.to_owned()));
-                }
+            let name = if lname.starts_with('.') {
+                // local label, scoped to the last global one like the references the parser resolved
+                let parent = state.label_order.last().map_or("", |p| p.0.as_str());
+                format!("{}{}", parent, lname)
             } else {
-                // handle all previous labels and local labels
-                emit_deferred(spec, state)?;
-                state.local_label_set.clear();
-
-                if state
-                    .label_set
-                    .insert(lname.to_owned(), state.out_pos as u64)
-                    .is_some()
-                {
-                    return Err(EmitError::DuplicateLabel(lname.to_owned()));
-                }
+                lname.to_owned()
+            };
+            if state
+                .label_set
+                .insert(name.clone(), state.address())
+                .is_some()
+            {
+                return Err(EmitError::DuplicateLabel(name));
+            }
+            // numeric labels were already given unique names by the parser and don't start a scope
+            if !lname.starts_with(|c: char| c == '.' || c.is_ascii_digit()) {
+                state.label_order.push((name, state.current));
             }
+            resolve_pending_consts(spec, state)?;
             Ok(())
         }
         Instruction(iname, args) => {
             match iname.as_ref() {
-                // .org ADDRESS
+                // .org ADDRESS, an address the code runs at inside a `.phase` block
                 ".org" | ".ORG" => {
                     if args.len() != 1 {
                         return Err(EmitError::InvalidArgumentCount(iname.clone()));
                     }
-                    if let (Node::Argument(box Node::Integer(adr)), _) = args[0].emitter_simplify(
-                        &|cname| state.find_const(cname, spec),
-                        state.out_pos as u64,
-                    ) {
-                        let new_out_pos = adr as usize;
-                        if new_out_pos > state.out_buf.len() {
-                            state
-                                .out_buf
-                                .reserve(new_out_pos - state.out_buf.len() + 32 * 32);
-                            state.out_buf.resize(new_out_pos, 0);
-                        }
-                        state.out_pos = new_out_pos;
+                    let adr = immediate_value(spec, state, iname, args, 0)?
+                        .wrapping_sub(state.section().phase);
+                    let start = state.start_of(state.current);
+                    if adr < start {
+                        return Err(EmitError::OrgBeforeSection(
+                            adr,
+                            state.section().name.clone(),
+                        ));
+                    }
+                    if adr - start > MAX_SECTION_SIZE {
+                        return Err(EmitError::SectionTooLarge(
+                            state.section().name.clone(),
+                            adr - start,
+                        ));
+                    }
+                    let new_pos = (adr - start) as usize;
+                    let section = &mut state.sections[state.current];
+                    section.extend_to(new_pos);
+                    section.pos = new_pos;
+                    Ok(())
+                }
+                // .phase ADDRESS, the code that follows runs at ADDRESS but is stored here
+                ".phase" | ".PHASE" => {
+                    if args.len() != 1 {
+                        return Err(EmitError::InvalidArgumentCount(iname.clone()));
+                    }
+                    let adr = immediate_value(spec, state, iname, args, 0)?;
+                    let stored = state.address_at(state.current, state.section().pos, 0);
+                    state.sections[state.current].phase = adr.wrapping_sub(stored);
+                    Ok(())
+                }
+                ".dephase" | ".DEPHASE" => {
+                    if !args.is_empty() {
+                        return Err(EmitError::InvalidArgumentCount(iname.clone()));
+                    }
+                    state.sections[state.current].phase = 0;
+                    Ok(())
+                }
+                ".text" | ".TEXT" | ".data" | ".DATA" | ".rodata" | ".RODATA" | ".bss" | ".BSS" => {
+                    if !args.is_empty() {
+                        return Err(EmitError::InvalidArgumentCount(iname.clone()));
+                    }
+                    state.switch_section(&iname.to_ascii_lowercase());
+                    Ok(())
+                }
+                // .section NAME, the parser turns the name into a string
+                ".section" | ".SECTION" => match args.as_slice() {
+                    [Node::Argument(box Node::StringLiteral(name))] => {
+                        state.switch_section(&String::from_utf8_lossy(name));
                         Ok(())
+                    }
+                    [_] => Err(EmitError::InvalidArgumentType(iname.clone(), 0)),
+                    _ => Err(EmitError::InvalidArgumentCount(iname.clone())),
+                },
+                // .balign BYTES[, FILL[, MAX-SKIP]], .p2align/.align POWER-OF-2[, FILL[, MAX-SKIP]]
+                ".balign" | ".BALIGN" | ".p2align" | ".P2ALIGN" | ".align" | ".ALIGN" => {
+                    if args.is_empty() || args.len() > 3 {
+                        return Err(EmitError::InvalidArgumentCount(iname.clone()));
+                    }
+                    let amount = immediate_value(spec, state, iname, args, 0)?;
+                    let align = if iname.eq_ignore_ascii_case(".balign") {
+                        amount
                     } else {
-                        Err(EmitError::InvalidArgumentType(iname.clone(), 0))
+                        // powers of 64 and more don't fit, even when truncated to a u32
+                        u32::try_from(amount)
+                            .ok()
+                            .and_then(|a| 1u64.checked_shl(a))
+                            .ok_or_else(|| {
+                                EmitError::AlignmentPowerOutOfRange(iname.clone(), amount)
+                            })?
+                    };
+                    if !align.is_power_of_two() {
+                        return Err(EmitError::InvalidAlignment(iname.clone(), amount));
                     }
+                    if align > MAX_SECTION_SIZE {
+                        return Err(EmitError::AlignmentTooLarge(iname.clone(), align));
+                    }
+                    let fill = match args.get(1) {
+                        None | Some(Node::Argument(box Node::Omitted)) => None,
+                        Some(_) => Some(immediate_value(spec, state, iname, args, 1)? as u8),
+                    };
+                    let max_skip = match args.get(2) {
+                        None => None,
+                        Some(_) => Some(immediate_value(spec, state, iname, args, 2)?),
+                    };
+                    // aligned within the section, which then starts at an aligned address too
+                    let section = &mut state.sections[state.current];
+                    section.align = section.align.max(align);
+                    let pos = section.pos as u64;
+                    let padding = pos.div_ceil(align) * align - pos;
+                    if max_skip.is_some_and(|max| padding > max) {
+                        return Ok(());
+                    }
+                    state.pad(spec, iname, padding as usize, fill)
+                }
+                // .space SIZE[, FILL], .skip SIZE[, FILL] and .zero SIZE
+                ".space" | ".SPACE" | ".skip" | ".SKIP" | ".zero" | ".ZERO" => {
+                    let max_args = if iname.eq_ignore_ascii_case(".zero") {
+                        1
+                    } else {
+                        2
+                    };
+                    if args.is_empty() || args.len() > max_args {
+                        return Err(EmitError::InvalidArgumentCount(iname.clone()));
+                    }
+                    let size = immediate_value(spec, state, iname, args, 0)?;
+                    if (size as i64) < 0 {
+                        return Err(EmitError::NegativeSize(iname.clone(), size as i64));
+                    }
+                    let fill = match args.len() {
+                        2 => immediate_value(spec, state, iname, args, 1)? as u8,
+                        _ => 0,
+                    };
+                    if fill != 0 {
+                        state.check_stored(iname)?;
+                    }
+                    state.accomodate_bytes(size as usize)?.fill(fill);
+                    Ok(())
                 }
                 // .equ/.define NAME VALUE
                 ".equ" | ".EQU" | ".define" | ".DEFINE" => {
                     if args.len() != 2 {
                         return Err(EmitError::InvalidArgumentCount(iname.clone()));
                     }
-                    if let Node::Argument(box Node::Identifier(defname)) = &args[0] {
-                        if let (Node::Argument(box Node::Integer(val)), _) = args[1]
-                            .emitter_simplify(
-                                &|cname| state.find_const(cname, spec),
-                                state.out_pos as u64,
-                            )
-                        {
-                            if state.const_set.insert(defname.to_owned(), val).is_none() {
-                                Ok(())
-                            } else {
-                                Err(EmitError::DuplicateConstant(defname.to_owned()))
-                            }
-                        } else {
-                            Err(EmitError::InvalidArgumentType(iname.clone(), 1))
-                        }
-                    } else {
-                        Err(EmitError::InvalidArgumentType(iname.clone(), 0))
+                    let defname = match &args[0] {
+                        Node::Argument(box Node::Identifier(defname)) => defname,
+                        _ => return Err(EmitError::InvalidArgumentType(iname.clone(), 0)),
+                    };
+                    if state.is_const_defined(defname) {
+                        return Err(EmitError::DuplicateConstant(defname.to_owned()));
+                    }
+                    let value = match &args[1] {
+                        Node::Argument(box value) => value,
+                        _ => return Err(EmitError::InvalidArgumentType(iname.clone(), 1)),
+                    };
+                    // the value may refer to labels further down, then it's resolved later,
+                    // with the values that `.set` constants have here
+                    let value = value
+                        .emitter_simplify(&state.symbols(spec), state.address())
+                        .map_err(|e| EmitError::InvalidExpression(iname.clone(), e))?
+                        .0;
+                    state.pending_consts.push((
+                        defname.to_owned(),
+                        value,
+                        state.address(),
+                        state.location.clone(),
+                        state.element,
+                    ));
+                    resolve_pending_consts(spec, state)?;
+                    Ok(())
+                }
+                // .set NAME, VALUE or NAME = VALUE
+                ".set" | ".SET" => {
+                    if args.len() != 2 {
+                        return Err(EmitError::InvalidArgumentCount(iname.clone()));
+                    }
+                    let defname = match &args[0] {
+                        Node::Argument(box Node::Identifier(defname)) => defname,
+                        _ => return Err(EmitError::InvalidArgumentType(iname.clone(), 0)),
+                    };
+                    if state.is_const_defined(defname) && !state.set_names.contains(defname) {
+                        return Err(EmitError::DuplicateConstant(defname.to_owned()));
                     }
+                    // uses see the value at their point in the source, so it must be known now
+                    let val = immediate_value(spec, state, iname, args, 1)?;
+                    state.const_set.insert(defname.to_owned(), val);
+                    state.set_names.insert(defname.to_owned());
+                    resolve_pending_consts(spec, state)?;
+                    Ok(())
+                }
+                // .incbin "FILE"[, OFFSET[, LENGTH]], the file contents were read in place of its name
+                ".incbin" | ".INCBIN" => {
+                    if args.is_empty() || args.len() > 3 {
+                        return Err(EmitError::InvalidArgumentCount(iname.clone()));
+                    }
+                    let data = match &args[0] {
+                        Node::Argument(box Node::StringLiteral(data)) => data,
+                        _ => return Err(EmitError::InvalidArgumentType(iname.clone(), 0)),
+                    };
+                    state.check_stored(iname)?;
+                    // the range decides where everything after it goes, so it must be known now
+                    let offset = match args.len() {
+                        1 => 0,
+                        _ => immediate_value(spec, state, iname, args, 1)?,
+                    };
+                    let length = match args.len() {
+                        3 => immediate_value(spec, state, iname, args, 2)?,
+                        _ => (data.len() as u64).saturating_sub(offset),
+                    };
+                    if offset
+                        .checked_add(length)
+                        .is_none_or(|end| end > data.len() as u64)
+                    {
+                        return Err(EmitError::IncbinRange(offset, length, data.len()));
+                    }
+                    state
+                        .accomodate_bytes(length as usize)?
+                        .copy_from_slice(&data[offset as usize..(offset + length) as usize]);
+                    Ok(())
                 }
                 // Standard RISC-V instructions
                 _ => {
@@ -181,33 +1013,42 @@ fn emit_binary_recurse(
                     let specinsn = spec
                         .get_instruction_by_name(iname)
                         .ok_or_else(|| EmitError::InvalidInstruction(iname.clone()))?;
-                    let fmt = specinsn.get_format(&spec);
+                    let fmt = specinsn.get_format(spec);
                     if args.len() != specinsn.args.len() {
                         return Err(EmitError::InvalidArgumentCount(iname.clone()));
                     }
 
+                    state.check_stored(iname)?;
                     // check length
-                    let ilen_bytes = (fmt.ilen + 7) / 8;
+                    let ilen_bytes = fmt.ilen.div_ceil(8);
                     if ilen_bytes > max_ilen_bytes {
                         return Err(EmitError::InvalidEncoding(iname.clone()));
                     }
                     // check alignment
-                    let aligned_pos =
-                        (state.out_pos + ialign_bytes - 1) / ialign_bytes * ialign_bytes;
-                    if state.out_pos != aligned_pos {
-                        // pad out with zeroes
-                        // TODO: NOP alignment instead of zero alignment
-                        state.accomodate_bytes(aligned_pos - state.out_pos);
+                    state.sections[state.current].code = true;
+                    let pos = state.section().pos;
+                    let aligned_pos = pos.div_ceil(ialign_bytes) * ialign_bytes;
+                    if pos != aligned_pos {
+                        state.pad(spec, iname, aligned_pos - pos, None)?;
                     }
 
                     // simplify and defer if necessary
-                    let simpinsn = node.emitter_simplify(
-                        &|cname| state.find_const(cname, spec),
-                        state.out_pos as u64,
-                    );
+                    record_pcrel_hi(spec, state, node, state.address());
+                    let simpinsn = node
+                        .emitter_simplify(&state.symbols(spec), state.address())
+                        .map_err(|e| EmitError::InvalidExpression(iname.clone(), e))?;
                     if !simpinsn.1 {
-                        state.deferred.push((state.out_pos, simpinsn.0));
-                        state.accomodate_bytes(ilen_bytes);
+                        state.deferred.push(DeferredInstruction {
+                            section: state.current,
+                            pos: aligned_pos,
+                            end: aligned_pos + ilen_bytes,
+                            store: state.stores,
+                            phase: state.section().phase,
+                            location: state.location.clone(),
+                            element: state.element,
+                            insn: simpinsn.0,
+                        });
+                        state.accomodate_bytes(ilen_bytes)?;
                         return Ok(());
                     }
                     let args;
@@ -240,7 +1081,7 @@ fn emit_binary_recurse(
                     assert_eq!(argv.len(), specinsn.args.len());
 
                     // emit instruction
-                    let bytes = state.accomodate_bytes(ilen_bytes);
+                    let bytes = state.accomodate_bytes(ilen_bytes)?;
                     specinsn
                         .encode_into(bytes, spec, argv.as_slice())
                         .map_err(|_| EmitError::InvalidEncoding(iname.clone()))
@@ -1 +1,2 @@
 pub mod flatbin;
+pub mod section;
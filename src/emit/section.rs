@@ -0,0 +1,212 @@
+use crate::parser::SourceLocation;
+use std::collections::{BTreeMap, HashMap};
+
+/// Alignment of the start of a section without alignment directives
+pub const SECTION_ALIGN: u64 = 4;
+
+/// Largest size of a section and its alignment, far beyond the memories of the targets,
+/// so that a mistyped size is an error instead of exhausting the memory of the host
+pub const MAX_SECTION_SIZE: u64 = 1 << 28;
+
+/// The element that stored a byte range, with a number unique to each storing so that the
+/// copies of an element made by macros and repetitions are told apart
+#[derive(Debug, Clone)]
+pub struct StoredBy {
+    pub id: usize,
+    pub loc: Option<SourceLocation>,
+}
+
+/// A named part of the output with its own location counter
+#[derive(Debug)]
+pub struct Section {
+    pub name: String,
+    pub data: Vec<u8>,
+    /// Location counter, relative to the start of the section
+    pub pos: usize,
+    /// Whether the section only reserves space without storing bytes in the output, like `.bss`
+    pub nobits: bool,
+    /// Size of a section that only reserves space, which keeps its `data` empty
+    pub reserved: usize,
+    /// Whether the section holds instructions, so that alignment pads it with NOPs
+    pub code: bool,
+    /// Alignment of the start of the section, the largest one requested in it
+    pub align: u64,
+    /// Difference between the address the code runs at and the one it is stored at,
+    /// inside a `.phase` block
+    pub phase: u64,
+    /// Byte ranges stored so far, the start of each one mapped to its end and the element
+    /// that stored it
+    pub stored: BTreeMap<usize, (usize, StoredBy)>,
+}
+
+impl Section {
+    pub fn new(name: &str) -> Self {
+        Section {
+            name: name.to_owned(),
+            data: Vec::new(),
+            pos: 0,
+            nobits: is_nobits(name),
+            reserved: 0,
+            code: name == ".text" || name.starts_with(".text."),
+            align: SECTION_ALIGN,
+            phase: 0,
+            stored: BTreeMap::new(),
+        }
+    }
+
+    /// Starts of the stored ranges with some of the bytes from `start` to `end`, last one first
+    pub fn stored_between(&self, start: usize, end: usize) -> Vec<usize> {
+        self.stored
+            .range(..end)
+            .rev()
+            .take_while(|(_, (e, _))| *e > start)
+            .map(|(s, _)| *s)
+            .collect()
+    }
+
+    /// Takes note of the bytes from `start` to `end` being stored by `by`. If some of them
+    /// were stored before, returns the first one and the location of the element that stored it.
+    pub fn record_stored(
+        &mut self,
+        start: usize,
+        end: usize,
+        by: StoredBy,
+    ) -> Option<(usize, Option<SourceLocation>)> {
+        let mut overlap = None;
+        for s in self.stored_between(start, end) {
+            // keep the parts of the earlier range that aren't overwritten
+            let (e, earlier) = self.stored.remove(&s).unwrap();
+            if s < start {
+                self.stored.insert(s, (start, earlier.clone()));
+            }
+            if e > end {
+                self.stored.insert(end, (e, earlier.clone()));
+            }
+            overlap = Some((s.max(start), earlier.loc));
+        }
+        self.stored.insert(start, (end, by));
+        overlap
+    }
+
+    pub fn size(&self) -> usize {
+        if self.nobits {
+            self.reserved
+        } else {
+            self.data.len()
+        }
+    }
+
+    /// Makes the section at least `size` bytes large, the new bytes are zeroes
+    pub fn extend_to(&mut self, size: usize) {
+        if self.nobits {
+            self.reserved = self.reserved.max(size);
+        } else if self.data.len() < size {
+            self.data.resize(size, 0);
+        }
+    }
+}
+
+fn is_nobits(name: &str) -> bool {
+    name == ".bss" || name.starts_with(".bss.") || name == ".sbss" || name.starts_with(".sbss.")
+}
+
+/// Where sections are placed in the output
+#[derive(Debug, Clone, Default)]
+pub struct LayoutOptions {
+    /// Start addresses of sections that aren't simply placed after the previous one
+    pub section_starts: Vec<(String, u64)>,
+    /// Address the start of the output is loaded at
+    pub base_address: u64,
+    /// Whether storing bytes where others were stored before is only a warning
+    pub allow_overlap: bool,
+}
+
+/// Rank of a section in the placement order: code, read-only data, data, anything else
+/// and sections that only reserve space last
+fn placement_rank(section: &Section) -> usize {
+    match section.name.as_str() {
+        _ if section.nobits => 4,
+        ".text" => 0,
+        ".rodata" => 1,
+        ".data" => 2,
+        _ => 3,
+    }
+}
+
+/// Start address of each section. Sections follow one another in placement order (keeping the
+/// order of appearance within a rank) from the base address, unless they have a start address
+/// in `options`.
+pub fn place_sections(sections: &[Section], options: &LayoutOptions) -> HashMap<String, u64> {
+    let mut order: Vec<&Section> = sections.iter().collect();
+    order.sort_by_key(|s| placement_rank(s));
+    let mut starts = HashMap::new();
+    let mut next = options.base_address;
+    for section in order {
+        let start = options
+            .section_starts
+            .iter()
+            .find(|(name, _)| *name == section.name)
+            .map_or(next.div_ceil(section.align) * section.align, |s| s.1);
+        next = start + section.size() as u64;
+        starts.insert(section.name.clone(), start);
+    }
+    starts
+}
+
+/// Sections storing bytes in the output, which must not start before the base address
+pub fn stored_sections(sections: &[Section]) -> impl Iterator<Item = &Section> + Clone {
+    sections.iter().filter(|s| !s.nobits && s.size() > 0)
+}
+
+/// Bytes that sections placed at `starts` store at the same address. For each pair of sections
+/// storing some, gives the one appearing first in the source, the other one, the lowest such
+/// address and the element of the other section storing it.
+pub fn section_overlaps(
+    sections: &[Section],
+    starts: &HashMap<String, u64>,
+) -> Vec<(String, String, u64, Option<SourceLocation>)> {
+    let stored: Vec<&Section> = stored_sections(sections).collect();
+    let mut overlaps = Vec::new();
+    for (idx, earlier) in stored.iter().enumerate() {
+        for later in &stored[idx + 1..] {
+            let (e_start, l_start) = (starts[&earlier.name], starts[&later.name]);
+            let first = later
+                .stored
+                .iter()
+                .flat_map(|(ls, (le, by))| {
+                    let (ls, le) = (l_start + *ls as u64, l_start + *le as u64);
+                    earlier.stored.iter().filter_map(move |(es, (ee, _))| {
+                        let (es, ee) = (e_start + *es as u64, e_start + *ee as u64);
+                        (es.max(ls) < ee.min(le)).then_some((es.max(ls), &by.loc))
+                    })
+                })
+                .min_by_key(|(address, _)| *address);
+            if let Some((address, loc)) = first {
+                overlaps.push((
+                    earlier.name.clone(),
+                    later.name.clone(),
+                    address,
+                    loc.clone(),
+                ));
+            }
+        }
+    }
+    overlaps
+}
+
+/// The bytes of all sections at their start addresses relative to `base`,
+/// gaps are filled with zeroes
+pub fn flat_image(sections: &[Section], starts: &HashMap<String, u64>, base: u64) -> Vec<u8> {
+    let stored = stored_sections(sections);
+    let end = stored
+        .clone()
+        .map(|s| (starts[&s.name] - base) as usize + s.size())
+        .max()
+        .unwrap_or(0);
+    let mut image = vec![0; end];
+    for section in stored {
+        let start = (starts[&section.name] - base) as usize;
+        image[start..start + section.size()].copy_from_slice(&section.data);
+    }
+    image
+}
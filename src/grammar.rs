@@ -1,5 +1,5 @@
 use crate::arch;
-use crate::parser::Node;
+use crate::parser::{MacroParam, Node, SourceLocation};
 
 peg::parser! { grammar asmpeg(spec: &arch::RiscVSpec) for str {
 rule comment() = quiet!{";" (!['\n'][_])+}
@@ -7,13 +7,14 @@ rule whitechar() = quiet!{[' '|'\r'|'\t']} / quiet!{comment()} / "\\\n"
 rule whitespace() = quiet!{whitechar()+}
 rule newline() = quiet!{whitespace()?} "\n"
 rule register() -> Node = quiet!{ s:$(['a'..='z'|'A'..='Z'|'.'|'_']['a'..='z'|'A'..='Z'|'0'..='9'|'.'|'_']*) {? Node::parse_register(spec, s) } } / expected!("register")
-rule idstr() -> &'input str = quiet!{ !register() sv:$(['a'..='z'|'A'..='Z'|'.'|'_']['a'..='z'|'A'..='Z'|'0'..='9'|'.'|'_']*) { sv } } / expected!("identifier")
+// inside macros, `\param` pastes an argument into the name, `\@` a unique number and `\()` nothing
+rule name_paste() = "\\" (param_name() / "@" / "()")
+rule idstr() -> &'input str = quiet!{ !register() sv:$((['a'..='z'|'A'..='Z'|'.'|'_'] / name_paste()) (['a'..='z'|'A'..='Z'|'0'..='9'|'.'|'_'] / name_paste())*) { sv } } / expected!("identifier")
+rule param_name() -> &'input str = $(['a'..='z'|'A'..='Z'|'_']['a'..='z'|'A'..='Z'|'0'..='9'|'_']*)
+rule macro_argument() -> Node = "\\" p:param_name() !(['a'..='z'|'A'..='Z'|'0'..='9'|'.'|'_'] / "\\") { Node::MacroArgument(p.to_owned()) }
 rule identifier() -> Node = s:idstr() { Node::Identifier(s.to_owned()) }
 
-rule integer() -> Node = quiet!{ "0x" n:$(['0'..='9'|'a'..='f'|'A'..='F'|'_']+) { Node::parse_u64(n, 16) } }
-        / quiet!{ "0o" n:$(['0'..='7'|'_']+) { Node::parse_u64(n, 8) } }
-        / quiet!{ "0b" n:$(['0'..='1'|'_']+) { Node::parse_u64(n, 2) } }
-        / quiet!{ "0d"? n:$(['0'..='9'|'_']+) { Node::parse_u64(n, 10) } }
+rule integer() -> Node = n:$(quiet!{['0'..='9'] ['0'..='9'|'a'..='z'|'A'..='Z'|'_']*}) {? Node::parse_integer(n) }
         / expected!("integer")
 
 rule escape() -> u8 = _:"\\n" {"\n".as_bytes()[0]} / _:"\\t" {"\t".as_bytes()[0]}
@@ -25,15 +26,55 @@ rule str_char<Q>(quote: rule<Q>) -> u8 = escape() / c:$(!quote() [_]) { c.as_byt
 rule char_literal() -> Node = "'" s:str_char(<"'">) "'" { Node::Integer(s as u64) }
 rule bytes_literal() -> Node = "\"" s:str_char(<"\"">)* "\"" { Node::StringLiteral(s) }
 
-rule negation() -> Node = "-" e:expression() { Node::Negation(Box::new(e)) }
+rule function_call() -> Node = name:$(['a'..='z'|'A'..='Z'|'_']['a'..='z'|'A'..='Z'|'0'..='9'|'_']*) whitespace()? "(" args:(expression() ** ",") ")" {? Node::parse_function(name, args) }
+rule relocation() -> Node = "%" k:$("pcrel_hi" / "pcrel_lo" / "hi" / "lo") whitespace()? "(" e:expression() ")" {
+    let e = Box::new(e);
+    match k {
+        "hi" => Node::Hi(e),
+        "lo" => Node::Lo(e),
+        "pcrel_hi" => Node::PcrelHi(e),
+        _ => Node::PcrelLo(e),
+    }
+}
+rule numeric_label_ref() -> Node = n:$(['0'..='9']+ ['b'|'f']) !['0'..='9'|'a'..='z'|'A'..='Z'|'_'] { Node::Identifier(n.to_owned()) }
+rule negation() -> Node = "-" e:expr_atom() { Node::Negation(Box::new(e)) }
+rule complement() -> Node = "~" e:expr_atom() { Node::Not(Box::new(e)) }
+rule logical_not() -> Node = "!" e:expr_atom() { Node::LogicalNot(Box::new(e)) }
 pub rule expr_atom() -> Node = whitespace()? "(" whitespace()? e:expression() whitespace()? ")" whitespace()? {e.simplify()}
                       / whitespace()? n:negation() whitespace()? {n.simplify()}
+                      / whitespace()? n:complement() whitespace()? {n.simplify()}
+                      / whitespace()? n:logical_not() whitespace()? {n.simplify()}
+                      / whitespace()? l:numeric_label_ref() whitespace()? {l}
                       / whitespace()? i:integer() whitespace()? {i}
+                      / whitespace()? f:function_call() whitespace()? {f.simplify()}
+                      / whitespace()? r:relocation() whitespace()? {r.simplify()}
+                      / whitespace()? m:macro_argument() whitespace()? {m}
                       / whitespace()? i:identifier() whitespace()? {i}
                       / whitespace()? "$" whitespace()? { Node::PcValue }
                       / whitespace()? c:char_literal() whitespace()? {c}
 
+// C operator precedence, from the loosest binding to the tightest
 pub rule expression() -> Node = precedence! {
+      c:@ "?" t:expression() ":" e:(@) { Node::Ternary(Box::new(c), Box::new(t), Box::new(e)).simplify() }
+      --
+      x:(@) "||" y:@ { Node::LogicalOr(Box::new(x), Box::new(y)).simplify() }
+      --
+      x:(@) "&&" y:@ { Node::LogicalAnd(Box::new(x), Box::new(y)).simplify() }
+      --
+      x:(@) "|" !"|" y:@ { Node::BitOr(Box::new(x), Box::new(y)).simplify() }
+      --
+      x:(@) "^" y:@ { Node::BitXor(Box::new(x), Box::new(y)).simplify() }
+      --
+      x:(@) "&" !"&" y:@ { Node::BitAnd(Box::new(x), Box::new(y)).simplify() }
+      --
+      x:(@) "==" y:@ { Node::Eq(Box::new(x), Box::new(y)).simplify() }
+      x:(@) "!=" y:@ { Node::Ne(Box::new(x), Box::new(y)).simplify() }
+      --
+      x:(@) "<=" y:@ { Node::Le(Box::new(x), Box::new(y)).simplify() }
+      x:(@) ">=" y:@ { Node::Ge(Box::new(x), Box::new(y)).simplify() }
+      x:(@) "<" !"<" y:@ { Node::Lt(Box::new(x), Box::new(y)).simplify() }
+      x:(@) ">" !">" y:@ { Node::Gt(Box::new(x), Box::new(y)).simplify() }
+      --
       x:(@) "<<" y:@ { Node::Shl(Box::new(x), Box::new(y)).simplify() }
       x:(@) ">>" y:@ { Node::Shr(Box::new(x), Box::new(y)).simplify() }
       x:(@) ">>>" y:@ { Node::Ashr(Box::new(x), Box::new(y)).simplify() }
@@ -43,22 +84,74 @@ pub rule expression() -> Node = precedence! {
       --
        x:(@) "*" y:@ { Node::Times(Box::new(x), Box::new(y)).simplify() }
       x:(@) "/" y:@ { Node::Divide(Box::new(x), Box::new(y)).simplify() }
+      x:(@) "%" y:@ { Node::Modulo(Box::new(x), Box::new(y)).simplify() }
       --
       a:expr_atom() {a}
 }
 
-pub rule label() -> Node = whitespace()? i:idstr() whitespace()? ":" { Node::Label(i.to_owned()) } / expected!("label")
-pub rule argument() -> Node = whitespace()? e:(register() / expression()) whitespace()? {Node::Argument(Box::new(e))}
+pub rule label() -> Node = whitespace()? i:$(idstr() / ['0'..='9']+) whitespace()? ":" { Node::Label(i.to_owned()) } / expected!("label")
+// NAME = VALUE, the same as .set NAME, VALUE
+rule assignment() -> Node = whitespace()? i:idstr() whitespace()? "=" !"=" v:argument() {
+    Node::Instruction(".set".to_owned(), vec![Node::Argument(Box::new(Node::Identifier(i.to_owned()))), v])
+}
+pub rule argument() -> Node = whitespace()? e:(register() / bytes_literal() / expression()) whitespace()? {Node::Argument(Box::new(e))}
+rule keyword_argument() -> Node = whitespace()? k:param_name() whitespace()? "=" !"=" a:argument() { Node::KeywordArgument(k.to_owned(), Box::new(a)) }
+rule call_argument() -> Node = keyword_argument() / argument()
 rule instruction0() -> Node = whitespace()? nm:idstr() whitespace()? { Node::Instruction(nm.to_owned(), vec![]) }
-rule instruction1() -> Node = whitespace()? nm:idstr() whitespace() a0:argument() whitespace()? { Node::Instruction(nm.to_owned(), vec![a0]) }
-rule instructionN() -> Node = whitespace()? nm:idstr() whitespace() a0:argument() aN:( "," an:argument() {an} )+ {
+rule instruction1() -> Node = whitespace()? nm:idstr() whitespace() a0:call_argument() whitespace()? { Node::Instruction(nm.to_owned(), vec![a0]) }
+rule instructionN() -> Node = whitespace()? nm:idstr() whitespace() a0:call_argument() aN:( "," an:call_argument() {an} )+ {
     let mut v = aN;
     v.insert(0, a0);
     Node::Instruction(nm.to_owned(), v)
 }
 pub rule instruction() -> Node = instructionN() / instruction1() / instruction0() / expected!("instruction")
 
-pub rule top_element() -> Node = (whitespace() / newline())* n:(label() / instruction()) {n}
+rule macro_param() -> MacroParam = whitespace()? n:param_name() whitespace()? v:(":" whitespace()? "vararg" whitespace()?)? d:("=" a:argument() {a})? {
+    let default = d.map(|a| match a {
+        Node::Argument(box value) => value,
+        other => other,
+    });
+    MacroParam { name: n.to_owned(), default, vararg: v.is_some() }
+}
+rule macro_end() = (whitespace() / newline())* ".endm" !['a'..='z'|'A'..='Z'|'0'..='9'|'.'|'_']
+rule macro_definition() -> Node = whitespace()? ".macro" whitespace() name:idstr() params:(macro_param() ** ",") body:(!macro_end() e:top_element() {e})* macro_end() {
+    Node::MacroDefinition(name.to_owned(), params, body)
+}
+
+rule repeat_end() = (whitespace() / newline())* ".endr" !['a'..='z'|'A'..='Z'|'0'..='9'|'.'|'_']
+rule repeat_body() -> Vec<Node> = body:(!repeat_end() e:top_element() {e})* repeat_end() { body }
+// each character of `.irpc` is a one-digit integer or a one-letter identifier
+rule irpc_char() -> Node = c:$(['0'..='9']) { Node::Integer(c.parse().unwrap()) }
+        / c:$(!['\n'|' '|'\t'|'\r'|';'] [_]) { Node::Identifier(c.to_owned()) }
+rule repetition() -> Node = whitespace()? ".rept" whitespace() c:expression() b:repeat_body() { Node::Repeat(Box::new(c), b) }
+    / whitespace()? ".irpc" whitespace() p:param_name() whitespace()? "," whitespace()? v:irpc_char()* b:repeat_body() {
+        Node::Iterate(p.to_owned(), v, b)
+    }
+    / whitespace()? ".irp" whitespace() p:param_name() whitespace()? v:("," a:argument() {a})* b:repeat_body() {
+        Node::Iterate(p.to_owned(), v, b)
+    }
+
+// section names are kept apart from expressions, where `.name` would refer to a local label
+rule section_name() -> &'input str = $(['a'..='z'|'A'..='Z'|'.'|'_']['a'..='z'|'A'..='Z'|'0'..='9'|'.'|'_'|'$']*)
+    / "\"" n:$((!['"'|'\n'] [_])*) "\"" { n }
+// the GNU flags, @type and entry size after the name only matter to linkers, they are skipped
+rule section_flag() = whitespace()? "," whitespace()? (bytes_literal() / ['@'|'%'] param_name() / expression())
+rule section() -> Node = whitespace()? ".section" whitespace() n:section_name() section_flag()* whitespace()? {
+    Node::Instruction(".section".to_owned(), vec![Node::Argument(Box::new(Node::StringLiteral(n.as_bytes().to_vec())))])
+}
+
+rule omitted_argument() -> Node = whitespace()? &"," { Node::Argument(Box::new(Node::Omitted)) }
+// .balign/.p2align/.align ALIGNMENT, FILL, MAX-SKIP where the fill can be left out
+rule alignment() -> Node = whitespace()? nm:$(".balign" / ".p2align" / ".align") !['a'..='z'|'A'..='Z'|'0'..='9'|'.'|'_'] whitespace()
+        a:argument() r:("," f:(omitted_argument() / argument()) {f})*<0,2> {
+    let mut args = vec![a];
+    args.extend(r);
+    Node::Instruction(nm.to_owned(), args)
+}
+
+pub rule top_element() -> Node = (whitespace() / newline())* p:position!() n:(label() / macro_definition() / repetition() / section() / alignment() / assignment() / instruction()) {
+    Node::Located(SourceLocation { offset: p, line: 0, file: None }, Box::new(n))
+}
 pub rule top_level() -> Node = n:(top_element()*) (whitespace() / newline())* { Node::Root(n) }
 
 }}
@@ -66,3 +159,10 @@ pub rule top_level() -> Node = n:(top_element()*) (whitespace() / newline())* {
 //include!{"../expanded.rs"}
 
 pub use asmpeg::top_level;
+
+/// Parses an expression on its own, where there are no numeric labels and `1010b` is binary
+pub fn expression(s: &str, spec: &arch::RiscVSpec) -> Result<Node, crate::parser::ParseError> {
+    let mut node = asmpeg::expression(s, spec)?;
+    crate::parser::resolve_binary_literals(&mut node);
+    Ok(node.simplify())
+}
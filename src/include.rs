@@ -0,0 +1,85 @@
+use crate::arch;
+use crate::parser::{parse_source, Node, SourceError, SourceLocation, SourceOptions};
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+
+/// Reads the file of an `.include "file"` element at `loc` and parses it into its elements.
+/// The file is looked up next to the file holding the element (in `dir` for a source string)
+/// first and then in the include paths, `including` holds the canonical paths of the files
+/// currently being included. Also gives the canonical path of the file.
+pub fn read_include(
+    args: &[Node],
+    loc: &SourceLocation,
+    spec: &arch::RiscVSpec,
+    options: &SourceOptions,
+    dir: &Path,
+    including: &[PathBuf],
+) -> Result<(PathBuf, Vec<Node>), SourceError> {
+    let name = match args {
+        [Node::Argument(box Node::StringLiteral(bytes))] => {
+            String::from_utf8_lossy(bytes).into_owned()
+        }
+        _ => return Err(SourceError::InvalidInclude),
+    };
+    let path = find_file(&name, source_dir(loc, dir), options, loc)?;
+    let canonical = path
+        .canonicalize()
+        .map_err(|e| SourceError::Io(path.display().to_string(), e.to_string()))?;
+    if including.contains(&canonical) {
+        return Err(SourceError::RecursiveInclude(name));
+    }
+    let source = std::fs::read_to_string(&path)
+        .map_err(|e| SourceError::Io(path.display().to_string(), e.to_string()))?;
+
+    let file: Rc<str> = Rc::from(path.to_string_lossy());
+    match parse_source(&source, spec, Some(&file))? {
+        Node::Root(nodes) => Ok((canonical, nodes)),
+        other => Ok((canonical, vec![other])),
+    }
+}
+
+/// Directory of the file holding the element at `loc`, or `dir` for a source string
+fn source_dir<'a>(loc: &'a SourceLocation, dir: &'a Path) -> &'a Path {
+    loc.file
+        .as_deref()
+        .and_then(|f| Path::new(f).parent())
+        .unwrap_or(dir)
+}
+
+/// Replaces the file name of each `.incbin` with the contents of the file,
+/// which is looked up like with `.include`
+pub fn read_binaries(root: &mut Node, options: &SourceOptions) -> Result<(), SourceError> {
+    let nodes = match root {
+        Node::Root(nodes) => nodes,
+        _ => return Ok(()),
+    };
+    for node in nodes.iter_mut() {
+        if let Node::Located(loc, box Node::Instruction(name, args)) = node {
+            if name != ".incbin" && name != ".INCBIN" {
+                continue;
+            }
+            if let Some(Node::Argument(box Node::StringLiteral(bytes))) = args.first_mut() {
+                let name = String::from_utf8_lossy(bytes).into_owned();
+                let path = find_file(&name, source_dir(loc, Path::new(".")), options, loc)?;
+                *bytes = std::fs::read(&path).map_err(|e| {
+                    SourceError::Io(path.display().to_string(), e.to_string()).located(loc)
+                })?;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Looks for `name` next to the including file in `dir`, then in the include paths
+fn find_file(
+    name: &str,
+    dir: &Path,
+    options: &SourceOptions,
+    loc: &SourceLocation,
+) -> Result<PathBuf, SourceError> {
+    std::iter::once(dir)
+        .chain(options.include_paths.iter().map(PathBuf::as_path))
+        .map(|d| d.join(name))
+        .find(|p| p.is_file())
+        .ok_or_else(|| SourceError::IncludeNotFound(name.to_owned()).located(loc))
+}
@@ -0,0 +1,368 @@
+use crate::arch;
+use crate::conditional::{is_conditional, Conditionals, Definitions};
+use crate::parser::{MacroParam, Node, SourceError, SourceLocation, SourceOptions};
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+
+/// How deep macros may expand into other macros before it's taken for endless recursion
+const MAX_EXPANSION_DEPTH: usize = 64;
+/// How many macro expansions, repetitions and resulting elements there may be, so that a
+/// mistyped `.rept` count is an error instead of assembling until the memory runs out
+pub const MAX_EXPANSION_SIZE: usize = 1 << 20;
+
+struct MacroDefinition {
+    params: Vec<MacroParam>,
+    body: Vec<Node>,
+}
+
+struct Expander<'a> {
+    spec: &'a arch::RiscVSpec,
+    options: &'a SourceOptions,
+    /// Directory that `.include`s in a source string are looked up in
+    dir: &'a Path,
+    /// Canonical paths of the files currently being included
+    including: Vec<PathBuf>,
+    macros: HashMap<String, MacroDefinition>,
+    /// Number of expansions so far, substituted for `\@`
+    expansions: usize,
+    defs: Definitions<'a>,
+}
+
+/// Removes `.macro` definitions from the root and replaces invocations of them with their bodies,
+/// keeps only the taken branches of conditional blocks and replaces the `.include`s in them with
+/// the elements of the file, all in source order. The expanded elements are located at the
+/// (outermost) invocation. The definitions of `options` become `.equ` constants ahead of the
+/// source.
+pub fn expand_macros(
+    root: Node,
+    spec: &arch::RiscVSpec,
+    options: &SourceOptions,
+    dir: &Path,
+    including: Vec<PathBuf>,
+) -> Result<Node, SourceError> {
+    let definitions = &options.definitions;
+    match root {
+        Node::Root(nodes) => {
+            let mut expander = Expander {
+                spec,
+                options,
+                dir,
+                including,
+                macros: HashMap::new(),
+                expansions: 0,
+                defs: Definitions::new(spec),
+            };
+            let mut out = Vec::with_capacity(definitions.len() + nodes.len());
+            for (name, value) in definitions {
+                expander.defs.define(name, Some(*value));
+                out.push(Node::Instruction(
+                    ".equ".to_owned(),
+                    vec![
+                        Node::Argument(Box::new(Node::Identifier(name.clone()))),
+                        Node::Argument(Box::new(Node::Integer(*value))),
+                    ],
+                ));
+            }
+            expander.expand_all(nodes, None, 0, &mut out)?;
+            Ok(Node::Root(out))
+        }
+        other => Ok(other),
+    }
+}
+
+impl Expander<'_> {
+    fn expand_all(
+        &mut self,
+        nodes: Vec<Node>,
+        site: Option<&SourceLocation>,
+        depth: usize,
+        out: &mut Vec<Node>,
+    ) -> Result<(), SourceError> {
+        let mut conditionals = Conditionals::default();
+        for node in nodes {
+            let (loc, inner) = match node {
+                Node::Located(loc, box inner) => (site.cloned().unwrap_or(loc), inner),
+                other => (site.cloned().unwrap_or_default(), other),
+            };
+            self.expand_one(&loc, site, inner, depth, &mut conditionals, out)
+                .map_err(|e| e.located(&loc))?;
+        }
+        conditionals.finish()
+    }
+
+    /// `site` is the location of the macro invocation that `node` was expanded from
+    fn expand_one(
+        &mut self,
+        loc: &SourceLocation,
+        site: Option<&SourceLocation>,
+        node: Node,
+        depth: usize,
+        conditionals: &mut Conditionals,
+        out: &mut Vec<Node>,
+    ) -> Result<(), SourceError> {
+        match node {
+            Node::Instruction(name, args) if is_conditional(&name) => {
+                conditionals.process(&name, &args, loc, &self.defs)?
+            }
+            _ if !conditionals.active() => {}
+            Node::MacroDefinition(name, params, body) => {
+                if self.macros.contains_key(&name) {
+                    return Err(SourceError::DuplicateMacro(name));
+                }
+                self.macros.insert(name, MacroDefinition { params, body });
+            }
+            Node::Instruction(name, args) if self.macros.contains_key(&name) => {
+                if depth >= MAX_EXPANSION_DEPTH {
+                    return Err(SourceError::MacroRecursion(name));
+                }
+                self.check_size(out)?;
+                let body = self.instantiate(&name, args)?;
+                self.expand_all(body, Some(loc), depth + 1, out)?;
+            }
+            Node::Instruction(name, args) if name == ".include" => {
+                let (canonical, nodes) = crate::include::read_include(
+                    &args,
+                    loc,
+                    self.spec,
+                    self.options,
+                    self.dir,
+                    &self.including,
+                )?;
+                self.including.push(canonical);
+                self.expand_all(nodes, site, depth, out)?;
+                self.including.pop();
+            }
+            Node::Repeat(box count, body) => {
+                let count = self.defs.value_of(&count)?;
+                if count > MAX_EXPANSION_SIZE as u64 {
+                    return Err(SourceError::RepeatCountOutOfRange(count as i64));
+                }
+                for _ in 0..count {
+                    self.check_size(out)?;
+                    let body = self.repetition(".rept", body.clone(), HashMap::new())?;
+                    self.expand_all(body, site, depth, out)?;
+                }
+            }
+            Node::Iterate(param, values, body) => {
+                for value in values {
+                    self.check_size(out)?;
+                    let args =
+                        std::iter::once((param.clone(), vec![argument_value(value)])).collect();
+                    let body = self.repetition(".irp", body.clone(), args)?;
+                    self.expand_all(body, site, depth, out)?;
+                }
+            }
+            other => {
+                self.check_size(out)?;
+                self.defs.record(&other);
+                out.push(Node::Located(loc.clone(), Box::new(other)))
+            }
+        }
+        Ok(())
+    }
+
+    fn check_size(&self, out: &[Node]) -> Result<(), SourceError> {
+        if self.expansions + out.len() >= MAX_EXPANSION_SIZE {
+            return Err(SourceError::ExpansionTooLarge);
+        }
+        Ok(())
+    }
+
+    /// The body of macro `name` with the arguments and the `\@` suffix substituted
+    fn instantiate(&mut self, name: &str, args: Vec<Node>) -> Result<Vec<Node>, SourceError> {
+        let def = &self.macros[name];
+        let unique = self.expansions.to_string();
+        self.expansions += 1;
+
+        let mut bound: HashMap<String, Vec<Node>> = HashMap::new();
+        let mut next_positional = 0;
+        for arg in args {
+            match arg {
+                Node::KeywordArgument(key, box value) => {
+                    if !def.params.iter().any(|p| p.name == key) {
+                        return Err(SourceError::UnknownMacroParameter(name.to_owned(), key));
+                    }
+                    bound.insert(key, vec![argument_value(value)]);
+                }
+                value => {
+                    let param = def
+                        .params
+                        .get(next_positional)
+                        .ok_or_else(|| SourceError::TooManyMacroArguments(name.to_owned()))?;
+                    let values = bound.entry(param.name.clone()).or_default();
+                    values.push(argument_value(value));
+                    if !param.vararg {
+                        next_positional += 1;
+                    }
+                }
+            }
+        }
+        for param in def.params.iter() {
+            if bound.contains_key(&param.name) {
+                continue;
+            }
+            let values = match &param.default {
+                Some(default) => vec![default.clone()],
+                None if param.vararg => vec![],
+                None => {
+                    return Err(SourceError::MissingMacroArgument(
+                        name.to_owned(),
+                        param.name.clone(),
+                    ))
+                }
+            };
+            bound.insert(param.name.clone(), values);
+        }
+
+        let mut body = def.body.clone();
+        for node in body.iter_mut() {
+            substitute(node, name, &bound, &unique)?;
+        }
+        Ok(body)
+    }
+
+    /// One round of a `.rept`/`.irp` body, with `args` and the `\@` suffix substituted
+    fn repetition(
+        &mut self,
+        directive: &str,
+        mut body: Vec<Node>,
+        args: HashMap<String, Vec<Node>>,
+    ) -> Result<Vec<Node>, SourceError> {
+        let unique = self.expansions.to_string();
+        self.expansions += 1;
+        for node in body.iter_mut() {
+            substitute(node, directive, &args, &unique)?;
+        }
+        Ok(body)
+    }
+}
+
+fn argument_value(arg: Node) -> Node {
+    match arg {
+        Node::Argument(box value) => value,
+        other => other,
+    }
+}
+
+fn substitute(
+    node: &mut Node,
+    name: &str,
+    args: &HashMap<String, Vec<Node>>,
+    unique: &str,
+) -> Result<(), SourceError> {
+    match node {
+        Node::MacroArgument(param) => {
+            *node = match args.get(param.as_str()).map(|v| v.as_slice()) {
+                Some([value]) => value.clone(),
+                Some(_) => {
+                    return Err(SourceError::InvalidMacroArgument(
+                        name.to_owned(),
+                        param.clone(),
+                    ))
+                }
+                None => {
+                    return Err(SourceError::UnknownMacroParameter(
+                        name.to_owned(),
+                        param.clone(),
+                    ))
+                }
+            };
+            return Ok(());
+        }
+        Node::Instruction(iname, iargs) => {
+            *iname = paste_name(iname, name, args, unique)?;
+            splice_arguments(iargs, args);
+        }
+        Node::Label(label) | Node::Identifier(label) => {
+            *label = paste_name(label, name, args, unique)?
+        }
+        // a nested definition is substituted when it gets expanded itself
+        Node::MacroDefinition(..) => return Ok(()),
+        // the parameter of a nested `.irp` is left for its own expansion
+        Node::Iterate(param, values, body) => {
+            let mut inner = args.clone();
+            inner.insert(param.clone(), vec![Node::MacroArgument(param.clone())]);
+            splice_arguments(values, args);
+            for value in values.iter_mut() {
+                substitute(value, name, args, unique)?;
+            }
+            for node in body.iter_mut() {
+                substitute(node, name, &inner, unique)?;
+            }
+            return Ok(());
+        }
+        _ => {}
+    }
+    for child in node.children_mut() {
+        substitute(child, name, args, unique)?;
+    }
+    Ok(())
+}
+
+/// A parameter making up a whole argument expands into all of its values (varargs)
+fn splice_arguments(list: &mut Vec<Node>, args: &HashMap<String, Vec<Node>>) {
+    let mut spliced = Vec::with_capacity(list.len());
+    for arg in list.drain(..) {
+        match arg {
+            Node::Argument(box Node::MacroArgument(param)) if args.contains_key(&param) => spliced
+                .extend(
+                    args[&param]
+                        .iter()
+                        .map(|v| Node::Argument(Box::new(v.clone()))),
+                ),
+            other => spliced.push(other),
+        }
+    }
+    *list = spliced;
+}
+
+/// Replaces `\param`, `\@` and `\()` inside a symbol or instruction name
+fn paste_name(
+    text: &str,
+    name: &str,
+    args: &HashMap<String, Vec<Node>>,
+    unique: &str,
+) -> Result<String, SourceError> {
+    if !text.contains('\\') {
+        return Ok(text.to_owned());
+    }
+    let mut out = String::with_capacity(text.len());
+    let mut rest = text;
+    while let Some(pos) = rest.find('\\') {
+        out.push_str(&rest[..pos]);
+        rest = &rest[pos + 1..];
+        if let Some(tail) = rest.strip_prefix('@') {
+            out.push_str(unique);
+            rest = tail;
+        } else if let Some(tail) = rest.strip_prefix("()") {
+            rest = tail;
+        } else {
+            let len = rest
+                .find(|c: char| !(c.is_ascii_alphanumeric() || c == '_'))
+                .unwrap_or(rest.len());
+            let param = &rest[..len];
+            rest = &rest[len..];
+            match args.get(param).map(|v| v.as_slice()) {
+                Some([Node::Identifier(value)]) => out.push_str(value),
+                Some([Node::Integer(value)]) => out.push_str(&value.to_string()),
+                Some([Node::MacroArgument(inner)]) => {
+                    out.push_str(&format!("\\{}\\()", inner));
+                }
+                Some(_) => {
+                    return Err(SourceError::InvalidMacroArgument(
+                        name.to_owned(),
+                        param.to_owned(),
+                    ))
+                }
+                None => {
+                    return Err(SourceError::UnknownMacroParameter(
+                        name.to_owned(),
+                        param.to_owned(),
+                    ))
+                }
+            }
+        }
+    }
+    out.push_str(rest);
+    Ok(out)
+}
@@ -2,9 +2,14 @@
 #![warn(clippy::all)]
 #![allow(dead_code)]
 mod arch;
+mod conditional;
 mod emit;
 mod grammar;
+mod include;
+mod macros;
 mod parser;
+mod specdump;
+#[cfg(test)]
 mod test;
 
 use emit::flatbin;
@@ -27,6 +32,30 @@ impl std::str::FromStr for OutputFormat {
     }
 }
 
+#[derive(Debug, Clone, StructOpt)]
+enum Command {
+    #[structopt(
+        name = "spec-dump",
+        about = "Write reference documentation of the loaded instruction set"
+    )]
+    SpecDump {
+        #[structopt(
+            short = "f",
+            long = "format",
+            default_value = "markdown",
+            help = "Documentation format: `markdown`, `html` or `json`"
+        )]
+        format: specdump::DumpFormat,
+
+        #[structopt(
+            short = "o",
+            long = "output-file",
+            help = "Output file path, prints to the terminal if not given"
+        )]
+        output_file: Option<PathBuf>,
+    },
+}
+
 #[derive(Debug, Clone, StructOpt)]
 #[structopt(
     name = "rvasm",
@@ -69,6 +98,13 @@ struct Opt {
     )]
     cfg: Vec<PathBuf>,
 
+    #[structopt(
+        long = "opcodes",
+        number_of_values = 1,
+        help = "Additional instruction definition files in the riscv-opcodes format to parse"
+    )]
+    opcodes: Vec<PathBuf>,
+
     #[structopt(
         short = "a",
         long = "arch",
@@ -77,45 +113,125 @@ struct Opt {
     )]
     arch: String,
 
+    #[structopt(
+        short = "I",
+        long = "include",
+        number_of_values = 1,
+        help = "Additional directories searched for `.include`d files"
+    )]
+    include_paths: Vec<PathBuf>,
+
+    #[structopt(
+        short = "D",
+        long = "define",
+        number_of_values = 1,
+        parse(try_from_str = parse_definition),
+        help = "Defines a constant for the source as NAME=VALUE, or NAME for the value 1"
+    )]
+    definitions: Vec<(String, u64)>,
+
+    #[structopt(
+        long = "section-start",
+        number_of_values = 1,
+        parse(try_from_str = parse_section_start),
+        help = "Places a section at an address instead of after the previous one, as NAME=ADDRESS"
+    )]
+    section_starts: Vec<(String, u64)>,
+
+    #[structopt(
+        long = "base-address",
+        default_value = "0",
+        parse(try_from_str = parse_value),
+        help = "Address the output file is loaded at, the first byte of the file is at this address"
+    )]
+    base_address: u64,
+
+    #[structopt(
+        long = "allow-overlap",
+        help = "Only warn about bytes stored where others were stored before, the later ones are kept"
+    )]
+    allow_overlap: bool,
+
     #[structopt(
         short = "b",
         long = "binary",
         help = "In addition to writing a file, print the assembly in binary to the terminal"
     )]
     print_binary: bool,
+
+    #[structopt(subcommand)]
+    cmd: Option<Command>,
 }
 
-fn main() {
-    let opt = Opt::from_args();
-    if opt.input_string.is_none() && opt.input_file.is_none() {
-        Opt::clap().print_long_help().unwrap();
-        eprintln!("A source file or string is required");
-        return;
-    }
-    if opt.input_string.is_some() && opt.input_file.is_some() {
-        Opt::clap().print_long_help().unwrap();
-        eprintln!("Only one source allowed: either a file or a string");
-        return;
+fn parse_value(value: &str) -> Result<u64, String> {
+    let (negative, literal) = match value.strip_prefix('-') {
+        Some(literal) => (true, literal),
+        None => (false, value),
+    };
+    match parser::Node::parse_integer(literal) {
+        Ok(parser::Node::Integer(v)) if negative => Ok(v.wrapping_neg()),
+        Ok(parser::Node::Integer(v)) => Ok(v),
+        _ => Err(format!("'{}' is not a valid integer", value)),
     }
+}
+
+fn parse_definition(s: &str) -> Result<(String, u64), String> {
+    let (name, value) = s.split_once('=').unwrap_or((s, "1"));
+    Ok((name.to_owned(), parse_value(value)?))
+}
 
-    let mut std_path = Vec::new();
-    std_path.push(PathBuf::from("./cfg/"));
+fn parse_section_start(s: &str) -> Result<(String, u64), String> {
+    let (name, address) = s
+        .split_once('=')
+        .ok_or_else(|| format!("'{}' is not of the form NAME=ADDRESS", s))?;
+    Ok((name.to_owned(), parse_value(address)?))
+}
+
+fn main() {
+    let opt = Opt::from_args();
+    let std_path = vec![PathBuf::from("./cfg/")];
 
     let mut rv = crate::arch::RiscVSpec::new();
     if let Err(e) = rv.load_arch_cfg(&std_path, &opt.arch, opt.verbose) {
-        eprintln!("Error loading arch-defined configuration: {:?}", e);
+        eprintln!("Error loading arch-defined configuration: {}", e);
         std::process::exit(1);
     }
-    for cfg in opt.cfg {
-        if let Err(e) = rv.load_single_cfg_file(&cfg) {
-            let pstr = cfg.as_os_str().to_string_lossy();
-            eprintln!(
-                "Error loading additional configuration from {}: {:?}",
-                pstr, e
-            );
+    for cfg in opt.cfg.iter() {
+        if let Err(e) = rv.load_single_cfg_file(cfg) {
+            eprintln!("Error loading additional configuration: {}", e);
             std::process::exit(1);
         }
     }
+    for opcodes in opt.opcodes.iter() {
+        match rv.load_opcodes_file(opcodes) {
+            Ok(skipped) => {
+                for warning in skipped {
+                    eprintln!("Warning: {}", warning);
+                }
+            }
+            Err(e) => {
+                eprintln!("Error loading riscv-opcodes definitions: {}", e);
+                std::process::exit(1);
+            }
+        }
+    }
+
+    if let Some(Command::SpecDump {
+        format,
+        ref output_file,
+    }) = opt.cmd
+    {
+        let doc = specdump::dump(&rv, format);
+        if let Some(output_file) = output_file {
+            if let Err(e) = std::fs::write(output_file, doc) {
+                eprintln!("Error writing {}: {}", output_file.display(), e);
+                std::process::exit(1);
+            }
+        } else {
+            print!("{}", doc);
+        }
+        return;
+    }
 
     if opt.verbose {
         for abi in rv.get_loaded_abis() {
@@ -126,36 +242,60 @@ fn main() {
         }
     }
 
+    if opt.input_string.is_none() && opt.input_file.is_none() {
+        Opt::clap().print_long_help().unwrap();
+        eprintln!("A source file or string is required");
+        return;
+    }
+    if opt.input_string.is_some() && opt.input_file.is_some() {
+        Opt::clap().print_long_help().unwrap();
+        eprintln!("Only one source allowed: either a file or a string");
+        return;
+    }
+
+    let source_options = parser::SourceOptions {
+        include_paths: opt.include_paths.clone(),
+        definitions: opt.definitions.clone(),
+    };
     let ast;
     if let Some(ref istr) = opt.input_string {
-        ast = parser::ast_from_str(&istr.replace(";", "\n"), &rv);
+        ast = parser::ast_from_str_with_options(&istr.replace(";", "\n"), &rv, &source_options);
     } else {
-        ast = parser::ast_from_file(
+        ast = parser::ast_from_file_with_options(
             opt.input_file
                 .as_ref()
                 .unwrap()
                 .to_str()
                 .expect("Invalid Unicode in specified file path"),
             &rv,
+            &source_options,
         );
     }
     if let Err(e) = ast {
-        eprintln!("Parse error: {:?}", e);
+        eprintln!("Parse error: {}", e);
         std::process::exit(1);
     }
     let ast = ast.unwrap();
 
-    use std::convert::TryInto;
     let bin: Vec<u8>;
 
     match opt.output_format {
         OutputFormat::Flat => {
-            let ebin = flatbin::emit_flat_binary(&rv, &ast);
+            let layout = emit::section::LayoutOptions {
+                section_starts: opt.section_starts.clone(),
+                base_address: opt.base_address,
+                allow_overlap: opt.allow_overlap,
+            };
+            let ebin = flatbin::emit_flat_binary_with_layout(&rv, &ast, &layout);
             if let Err(e) = ebin {
-                eprintln!("Binary emission error: {:?}", e);
+                eprintln!("Binary emission error: {}", e);
                 std::process::exit(1);
             } else {
-                bin = ebin.unwrap();
+                let (ebin, warnings) = ebin.unwrap();
+                for warning in warnings {
+                    eprintln!("Warning: {}", warning);
+                }
+                bin = ebin;
             }
         }
     }
@@ -163,9 +303,17 @@ fn main() {
     if opt.print_binary {
         println!("Binary assembly:");
         let mut cnt = 0;
-        for word in bin.chunks(4) {
-            let word: [u8; 4] = word.try_into().unwrap();
-            print!("{:032b} ", u32::from_le_bytes(word));
+        let mut pos = 0;
+        while pos < bin.len() {
+            // print each instruction with the length given by its opcode bits, MSB first
+            let len = arch::encoded_instruction_length(&bin[pos..])
+                .unwrap_or(2)
+                .min(bin.len() - pos);
+            for byte in bin[pos..pos + len].iter().rev() {
+                print!("{:08b}", byte);
+            }
+            print!(" ");
+            pos += len;
             if cnt == 1 {
                 println!();
                 cnt = 0;
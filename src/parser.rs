@@ -1,5 +1,8 @@
 use crate::arch;
 use crate::grammar;
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::rc::Rc;
 
 #[derive(Debug, Clone)]
 pub enum Node {
@@ -8,26 +11,275 @@ pub enum Node {
     StringLiteral(Vec<u8>),
     Register(i32),
     PcValue,
+    /// Argument left out between commas, like the fill of `.balign 16, , 8`
+    Omitted,
 
     Negation(Box<Self>),
+    /// Bitwise complement `~`
+    Not(Box<Self>),
+    /// Logical negation `!`
+    LogicalNot(Box<Self>),
     Plus(Box<Self>, Box<Self>),
     Minus(Box<Self>, Box<Self>),
     Times(Box<Self>, Box<Self>),
     Divide(Box<Self>, Box<Self>),
+    Modulo(Box<Self>, Box<Self>),
     Shl(Box<Self>, Box<Self>),
     Shr(Box<Self>, Box<Self>),
     Ashr(Box<Self>, Box<Self>),
+    Eq(Box<Self>, Box<Self>),
+    Ne(Box<Self>, Box<Self>),
+    Lt(Box<Self>, Box<Self>),
+    Le(Box<Self>, Box<Self>),
+    Gt(Box<Self>, Box<Self>),
+    Ge(Box<Self>, Box<Self>),
+    BitAnd(Box<Self>, Box<Self>),
+    BitOr(Box<Self>, Box<Self>),
+    BitXor(Box<Self>, Box<Self>),
+    LogicalAnd(Box<Self>, Box<Self>),
+    LogicalOr(Box<Self>, Box<Self>),
+    /// `condition ? then : else`
+    Ternary(Box<Self>, Box<Self>, Box<Self>),
+    /// `%hi(x)`: upper bits of x in place, rounded so that adding `%lo(x)` gives back x
+    Hi(Box<Self>),
+    /// `%lo(x)`: sign-extended lower 12 bits of x
+    Lo(Box<Self>),
+    /// `%pcrel_hi(sym)`: `%hi(sym - pc)`
+    PcrelHi(Box<Self>),
+    /// `%pcrel_lo(label)`: `%lo` part matching the `%pcrel_hi` of the instruction at label
+    PcrelLo(Box<Self>),
+    /// Built-in function call: name and arguments
+    Function(String, Vec<Node>),
 
     Label(String),
     Argument(Box<Node>),
+    /// A top-level element and where it was found
+    Located(SourceLocation, Box<Node>),
+    /// `name=value` argument of a macro invocation
+    KeywordArgument(String, Box<Node>),
+
+    /// `.macro`: name, parameters and body
+    MacroDefinition(String, Vec<MacroParam>, Vec<Node>),
+    /// `\param` inside a macro body
+    MacroArgument(String),
+    /// `.rept`: count and body
+    Repeat(Box<Node>, Vec<Node>),
+    /// `.irp`/`.irpc`: parameter, the values it takes in turn and body
+    Iterate(String, Vec<Node>, Vec<Node>),
     Instruction(String, Vec<Node>),
 
     Root(Vec<Node>),
 }
 
+/// Parameter of a `.macro`: `name`, `name=default` or `name:vararg`
+#[derive(Debug, Clone)]
+pub struct MacroParam {
+    pub name: String,
+    /// Argument node used when the invocation doesn't give one
+    pub default: Option<Node>,
+    /// Takes all the remaining positional arguments
+    pub vararg: bool,
+}
+
+/// Position of a top-level element in the source
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct SourceLocation {
+    /// Byte offset from the start of the source
+    pub offset: usize,
+    /// 1-based line number
+    pub line: usize,
+    /// Path of the source file, None for the main source when it isn't read from a file
+    pub file: Option<Rc<str>>,
+}
+
+impl std::fmt::Display for SourceLocation {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match &self.file {
+            Some(file) => write!(f, "{}:{}", file, self.line),
+            None => write!(f, "line {}", self.line),
+        }
+    }
+}
+
+/// Symbol lookups used when evaluating expressions,
+/// implemented for plain `Fn(&str) -> Option<u64>` closures
+pub trait SymbolProvider {
+    fn get_symbol(&self, name: &str) -> Option<u64>;
+
+    /// Value of `sym - pc` for the `%pcrel_hi(sym)` operand of the instruction at `address`
+    fn get_pcrel_hi(&self, _address: u64) -> Option<u64> {
+        None
+    }
+
+    /// Size of the region from label `name` up to the next label, for `sizeof(name)`
+    fn get_size(&self, _name: &str) -> Option<u64> {
+        None
+    }
+
+    /// Whether `name` is defined, for `defined(name)`; None if that isn't known yet
+    fn is_defined(&self, name: &str) -> Option<bool> {
+        Some(self.get_symbol(name).is_some())
+    }
+}
+
+impl<F: Fn(&str) -> Option<u64>> SymbolProvider for F {
+    fn get_symbol(&self, name: &str) -> Option<u64> {
+        self(name)
+    }
+}
+
+/// Errors from evaluating an expression whose operands are all known
+#[derive(Clone, Debug, PartialEq)]
+pub enum EvalError {
+    DivisionByZero,
+    /// Shift amount outside of 0..=63
+    ShiftOutOfRange(u64),
+    /// Operator and operands whose result doesn't fit in 64 bits, signed or unsigned
+    Overflow(&'static str, u64, u64),
+    InvalidArgument(String),
+}
+
+impl std::fmt::Display for EvalError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            EvalError::DivisionByZero => write!(f, "division by zero"),
+            EvalError::ShiftOutOfRange(n) => {
+                write!(f, "shift amount {} out of range 0..=63", *n as i64)
+            }
+            EvalError::Overflow(op, a, b) => {
+                write!(f, "{:#x} {} {:#x} overflows 64 bits", a, op, b)
+            }
+            EvalError::InvalidArgument(call) => write!(f, "invalid argument in {}", call),
+        }
+    }
+}
+
+impl std::error::Error for EvalError {}
+
+/// `a op b` on the exact values of the operands, read as both unsigned or both signed;
+/// fails only if neither result is representable in 64 bits.
+fn checked_arith(
+    op: &'static str,
+    a: u64,
+    b: u64,
+    f: fn(i128, i128) -> Option<i128>,
+) -> Result<u64, EvalError> {
+    let fits = |r: Option<i128>| r.filter(|r| *r >= i64::MIN as i128 && *r <= u64::MAX as i128);
+    fits(f(a as i128, b as i128))
+        .or_else(|| fits(f(a as i64 as i128, b as i64 as i128)))
+        .map(|r| r as u64)
+        .ok_or(EvalError::Overflow(op, a, b))
+}
+
+fn signed_div(
+    op: &'static str,
+    a: u64,
+    b: u64,
+    f: fn(i64, i64) -> Option<i64>,
+) -> Result<u64, EvalError> {
+    if b == 0 {
+        return Err(EvalError::DivisionByZero);
+    }
+    f(a as i64, b as i64)
+        .map(|r| r as u64)
+        .ok_or(EvalError::Overflow(op, a, b))
+}
+
+fn shift_amount(b: u64) -> Result<u64, EvalError> {
+    if b < 64 {
+        Ok(b)
+    } else {
+        Err(EvalError::ShiftOutOfRange(b))
+    }
+}
+
+/// `a << b`, which like the other operators fails if bits are lost with `a` read as both
+/// unsigned and signed
+fn checked_shl(a: u64, b: u64) -> Result<u64, EvalError> {
+    let shift = shift_amount(b)? as u32;
+    let r = a.checked_shl(shift).ok_or(EvalError::ShiftOutOfRange(b))?;
+    if r >> shift == a || (r as i64) >> shift == a as i64 {
+        Ok(r)
+    } else {
+        Err(EvalError::Overflow("<<", a, b))
+    }
+}
+
+fn hi_part(x: u64) -> u64 {
+    x.wrapping_add(0x800) & !0xfff
+}
+
+fn lo_part(x: u64) -> u64 {
+    ((x & 0xfff) ^ 0x800).wrapping_sub(0x800)
+}
+
+/// Built-in functions, their number of arguments and the parse error for calls with another number
+const BUILTIN_FUNCTIONS: &[(&str, usize, &str)] = &[
+    ("log2", 1, "1 argument for 'log2'"),
+    ("align", 2, "2 arguments for 'align'"),
+    ("min", 2, "2 arguments for 'min'"),
+    ("max", 2, "2 arguments for 'max'"),
+    ("sext", 2, "2 arguments for 'sext'"),
+    ("zext", 2, "2 arguments for 'zext'"),
+    ("bits", 3, "3 arguments for 'bits'"),
+    ("udiv", 2, "2 arguments for 'udiv'"),
+    ("urem", 2, "2 arguments for 'urem'"),
+    ("ult", 2, "2 arguments for 'ult'"),
+    ("ule", 2, "2 arguments for 'ule'"),
+    ("ugt", 2, "2 arguments for 'ugt'"),
+    ("uge", 2, "2 arguments for 'uge'"),
+    ("sizeof", 1, "1 argument for 'sizeof'"),
+    ("defined", 1, "1 argument for 'defined'"),
+];
+
 impl Node {
-    pub fn parse_u64(s: &str, radix: u32) -> Self {
-        Node::Integer(u64::from_str_radix(&s.replace("_", ""), radix).unwrap())
+    /// Parses an integer literal with an optional `0x`/`0o`/`0b`/`0d` prefix or
+    /// `h`/`o`/`q`/`b`/`d` suffix (any case) and `_` digit separators.
+    /// A trailing `h` wins over the prefixes (`0BEh`), the prefixes over the other suffixes (`0x1b`).
+    pub fn parse_integer(literal: &str) -> Result<Self, &'static str> {
+        let lower = literal.to_ascii_lowercase();
+        let prefixed = |prefix: &str| lower.strip_prefix(prefix).filter(|d| !d.is_empty());
+        let (digits, radix) = if let Some(d) = lower.strip_suffix('h') {
+            (d, 16)
+        } else if let Some(d) = prefixed("0x") {
+            (d, 16)
+        } else if let Some(d) = prefixed("0o") {
+            (d, 8)
+        } else if let Some(d) = prefixed("0b") {
+            (d, 2)
+        } else if let Some(d) = prefixed("0d") {
+            (d, 10)
+        } else if let Some(d) = lower.strip_suffix('o').or_else(|| lower.strip_suffix('q')) {
+            (d, 8)
+        } else if let Some(d) = lower.strip_suffix('b') {
+            (d, 2)
+        } else if let Some(d) = lower.strip_suffix('d') {
+            (d, 10)
+        } else {
+            (lower.as_str(), 10)
+        };
+        let digits = digits.replace('_', "");
+        if digits.is_empty() || !digits.chars().all(|c| c.is_digit(radix)) {
+            return Err("integer literal with valid digits");
+        }
+        u64::from_str_radix(&digits, radix)
+            .map(Node::Integer)
+            .map_err(|_| "integer literal that fits in 64 bits")
+    }
+
+    pub fn parse_function(name: &str, args: Vec<Node>) -> Result<Self, &'static str> {
+        let (_, arity, arity_error) = BUILTIN_FUNCTIONS
+            .iter()
+            .find(|f| f.0 == name)
+            .ok_or("known function")?;
+        if args.len() != *arity {
+            return Err(arity_error);
+        }
+        // these take a symbol name rather than its value
+        if matches!(name, "sizeof" | "defined") && !matches!(args[0], Node::Identifier(_)) {
+            return Err("symbol name");
+        }
+        Ok(Node::Function(name.to_owned(), args))
     }
 
     pub fn parse_register(spec: &arch::RiscVSpec, name: &str) -> Result<Self, &'static str> {
@@ -35,119 +287,731 @@ impl Node {
             .map_or(Err("invalid register"), |i| Ok(Node::Register(i.index)))
     }
 
+    /// Folds the node if its operands are integers. Operations that can't be evaluated
+    /// are left as they are, to be reported by `try_simplify` once the emitter gets to them.
     pub fn simplify(self) -> Self {
-        use Node::*;
-        match self {
-            Negation(box Integer(i)) => Integer(i.wrapping_neg()),
-            Plus(box Integer(a), box Integer(b)) => Integer(a.wrapping_add(b)),
-            Minus(box Integer(a), box Integer(b)) => Integer(a.wrapping_sub(b)),
-            Times(box Integer(a), box Integer(b)) => Integer(a.wrapping_mul(b)),
-            Divide(box Integer(a), box Integer(b)) => Integer(a.wrapping_div(b)),
-            Shl(box Integer(a), box Integer(b)) => Integer(a << b),
-            Shr(box Integer(a), box Integer(b)) => Integer(a >> b),
-            Ashr(box Integer(a), box Integer(b)) => Integer((a as i64 >> b as i64) as u64),
+        match self.fold() {
+            Ok(Some(node)) => node,
             _ => self,
         }
     }
 
+    pub fn try_simplify(self) -> Result<Self, EvalError> {
+        Ok(self.fold()?.unwrap_or(self))
+    }
+
+    /// Evaluates the operator at the root of the node, `None` if its operands aren't known yet.
+    ///
+    /// Integers are 64-bit two's complement: `/`, `%`, `>>>` and the ordered comparisons
+    /// read them as signed, `>>`, `udiv`, `urem`, `ult`, `ule`, `ugt` and `uge` as unsigned.
+    fn fold(&self) -> Result<Option<Self>, EvalError> {
+        use Node::*;
+        let v = match self {
+            Negation(box Integer(i)) => i.wrapping_neg(),
+            Not(box Integer(i)) => !i,
+            LogicalNot(box Integer(i)) => (*i == 0) as u64,
+            Plus(box Integer(a), box Integer(b)) => checked_arith("+", *a, *b, i128::checked_add)?,
+            Minus(box Integer(a), box Integer(b)) => checked_arith("-", *a, *b, i128::checked_sub)?,
+            Times(box Integer(a), box Integer(b)) => checked_arith("*", *a, *b, i128::checked_mul)?,
+            Divide(box Integer(a), box Integer(b)) => signed_div("/", *a, *b, i64::checked_div)?,
+            Modulo(box Integer(a), box Integer(b)) => signed_div("%", *a, *b, i64::checked_rem)?,
+            Shl(box Integer(a), box Integer(b)) => checked_shl(*a, *b)?,
+            Shr(box Integer(a), box Integer(b)) => a >> shift_amount(*b)?,
+            Ashr(box Integer(a), box Integer(b)) => (*a as i64 >> shift_amount(*b)?) as u64,
+            Eq(box Integer(a), box Integer(b)) => (a == b) as u64,
+            Ne(box Integer(a), box Integer(b)) => (a != b) as u64,
+            Lt(box Integer(a), box Integer(b)) => ((*a as i64) < (*b as i64)) as u64,
+            Le(box Integer(a), box Integer(b)) => (*a as i64 <= *b as i64) as u64,
+            Gt(box Integer(a), box Integer(b)) => (*a as i64 > *b as i64) as u64,
+            Ge(box Integer(a), box Integer(b)) => (*a as i64 >= *b as i64) as u64,
+            BitAnd(box Integer(a), box Integer(b)) => a & b,
+            BitOr(box Integer(a), box Integer(b)) => a | b,
+            BitXor(box Integer(a), box Integer(b)) => a ^ b,
+            // short-circuiting, the other operand doesn't need to be known
+            LogicalAnd(box Integer(0), _) => 0,
+            LogicalAnd(box Integer(_), box Integer(b)) => (*b != 0) as u64,
+            LogicalOr(box Integer(a), _) if *a != 0 => 1,
+            LogicalOr(box Integer(_), box Integer(b)) => (*b != 0) as u64,
+            Ternary(box Integer(c), box t, box e) => {
+                return Ok(Some(if *c != 0 { t.clone() } else { e.clone() }));
+            }
+            Hi(box Integer(x)) => hi_part(*x),
+            Lo(box Integer(x)) => lo_part(*x),
+            Function(name, args) => match (name.as_ref(), args.as_slice()) {
+                ("log2", [Integer(0)])
+                | ("align", [Integer(_), Integer(0)])
+                | ("zext", [Integer(_), Integer(65..)])
+                | ("sext", [Integer(_), Integer(0)])
+                | ("sext", [Integer(_), Integer(65..)]) => return Err(self.invalid_argument()),
+                ("bits", [Integer(_), Integer(hi), Integer(lo)]) if hi < lo || *hi > 63 => {
+                    return Err(self.invalid_argument());
+                }
+                ("log2", [Integer(a)]) => 63 - a.leading_zeros() as u64,
+                ("align", [Integer(a), Integer(n)]) => match a.checked_add(n - 1) {
+                    Some(up) => up / n * n,
+                    None => return Err(EvalError::Overflow("aligned to", *a, *n)),
+                },
+                ("min", [Integer(a), Integer(b)]) => (*a as i64).min(*b as i64) as u64,
+                ("max", [Integer(a), Integer(b)]) => (*a as i64).max(*b as i64) as u64,
+                ("zext", [Integer(_), Integer(0)]) => 0,
+                ("zext", [Integer(a), Integer(n)]) => a & (u64::MAX >> (64 - n)),
+                ("sext", [Integer(a), Integer(n)]) => ((a << (64 - n)) as i64 >> (64 - n)) as u64,
+                ("bits", [Integer(a), Integer(hi), Integer(lo)]) => {
+                    (a >> lo) & (u64::MAX >> (63 - (hi - lo)))
+                }
+                ("udiv", [Integer(_), Integer(0)]) | ("urem", [Integer(_), Integer(0)]) => {
+                    return Err(EvalError::DivisionByZero);
+                }
+                ("udiv", [Integer(a), Integer(b)]) => a / b,
+                ("urem", [Integer(a), Integer(b)]) => a % b,
+                ("ult", [Integer(a), Integer(b)]) => (a < b) as u64,
+                ("ule", [Integer(a), Integer(b)]) => (a <= b) as u64,
+                ("ugt", [Integer(a), Integer(b)]) => (a > b) as u64,
+                ("uge", [Integer(a), Integer(b)]) => (a >= b) as u64,
+                _ => return Ok(None),
+            },
+            _ => return Ok(None),
+        };
+        Ok(Some(Integer(v)))
+    }
+
+    /// Error for a function called with integer arguments outside of its domain
+    fn invalid_argument(&self) -> EvalError {
+        let call = match self {
+            Node::Function(name, args) => {
+                let args: Vec<String> = args
+                    .iter()
+                    .map(|a| match a {
+                        Node::Integer(v) => (*v as i64).to_string(),
+                        other => format!("{:?}", other),
+                    })
+                    .collect();
+                format!("{}({})", name, args.join(", "))
+            }
+            other => format!("{:?}", other),
+        };
+        EvalError::InvalidArgument(call)
+    }
+
     /// Returns: the simplified node and whether all the constants were reduced to integers.
-    pub fn emitter_simplify<F: Fn(&str) -> Option<u64>>(
+    pub fn emitter_simplify<P: SymbolProvider + ?Sized>(
         &self,
-        const_provider: &F,
+        const_provider: &P,
         pc: u64,
-    ) -> (Self, bool) {
+    ) -> Result<(Self, bool), EvalError> {
         use Node::*;
-        let cloned_f = || (self.clone(), false);
-        let cloned_t = || (self.clone(), true);
+        let cloned_f = || Ok((self.clone(), false));
+        let cloned_t = || Ok((self.clone(), true));
         match self {
-            Identifier(ident) => const_provider(ident)
-                .map(|v| (Integer(v), true))
+            Identifier(ident) => const_provider
+                .get_symbol(ident)
+                .map(|v| Ok((Integer(v), true)))
                 .unwrap_or_else(cloned_f),
-            Label(lname) => const_provider(lname)
-                .map(|v| (Integer(v), true))
+            Label(lname) => const_provider
+                .get_symbol(lname)
+                .map(|v| Ok((Integer(v), true)))
                 .unwrap_or_else(cloned_f),
 
-            Integer(v) => (Integer(*v), true),
+            Integer(v) => Ok((Integer(*v), true)),
             StringLiteral(_) => cloned_t(),
             Register(_) => cloned_t(),
-            PcValue => (Integer(pc), true),
+            PcValue => Ok((Integer(pc), true)),
+            Omitted => cloned_t(),
 
             Negation(box a) => {
-                let sa = a.emitter_simplify(const_provider, pc);
-                (Negation(Box::new(sa.0)).simplify(), sa.1)
+                let sa = a.emitter_simplify(const_provider, pc)?;
+                Ok((Negation(Box::new(sa.0)).try_simplify()?, sa.1))
+            }
+            Not(box a) => {
+                let sa = a.emitter_simplify(const_provider, pc)?;
+                Ok((Not(Box::new(sa.0)).try_simplify()?, sa.1))
+            }
+            LogicalNot(box a) => {
+                let sa = a.emitter_simplify(const_provider, pc)?;
+                Ok((LogicalNot(Box::new(sa.0)).try_simplify()?, sa.1))
             }
             Plus(box a, box b) => {
-                let sa = a.emitter_simplify(const_provider, pc);
-                let sb = b.emitter_simplify(const_provider, pc);
-                (Plus(Box::new(sa.0), Box::new(sb.0)).simplify(), sa.1 && sb.1)
+                let sa = a.emitter_simplify(const_provider, pc)?;
+                let sb = b.emitter_simplify(const_provider, pc)?;
+                Ok((Plus(Box::new(sa.0), Box::new(sb.0)).try_simplify()?, sa.1 && sb.1))
             }
             Minus(box a, box b) => {
-                let sa = a.emitter_simplify(const_provider, pc);
-                let sb = b.emitter_simplify(const_provider, pc);
-                (Minus(Box::new(sa.0), Box::new(sb.0)).simplify(), sa.1 && sb.1)
+                let sa = a.emitter_simplify(const_provider, pc)?;
+                let sb = b.emitter_simplify(const_provider, pc)?;
+                Ok((Minus(Box::new(sa.0), Box::new(sb.0)).try_simplify()?, sa.1 && sb.1))
             }
             Times(box a, box b) => {
-                let sa = a.emitter_simplify(const_provider, pc);
-                let sb = b.emitter_simplify(const_provider, pc);
-                (Times(Box::new(sa.0), Box::new(sb.0)).simplify(), sa.1 && sb.1)
+                let sa = a.emitter_simplify(const_provider, pc)?;
+                let sb = b.emitter_simplify(const_provider, pc)?;
+                Ok((Times(Box::new(sa.0), Box::new(sb.0)).try_simplify()?, sa.1 && sb.1))
             }
             Divide(box a, box b) => {
-                let sa = a.emitter_simplify(const_provider, pc);
-                let sb = b.emitter_simplify(const_provider, pc);
-                (Divide(Box::new(sa.0), Box::new(sb.0)).simplify(), sa.1 && sb.1)
+                let sa = a.emitter_simplify(const_provider, pc)?;
+                let sb = b.emitter_simplify(const_provider, pc)?;
+                Ok((Divide(Box::new(sa.0), Box::new(sb.0)).try_simplify()?, sa.1 && sb.1))
+            }
+            Modulo(box a, box b) => {
+                let sa = a.emitter_simplify(const_provider, pc)?;
+                let sb = b.emitter_simplify(const_provider, pc)?;
+                Ok((Modulo(Box::new(sa.0), Box::new(sb.0)).try_simplify()?, sa.1 && sb.1))
             }
             Shl(box a, box b) => {
-                let sa = a.emitter_simplify(const_provider, pc);
-                let sb = b.emitter_simplify(const_provider, pc);
-                (Shl(Box::new(sa.0), Box::new(sb.0)).simplify(), sa.1 && sb.1)
+                let sa = a.emitter_simplify(const_provider, pc)?;
+                let sb = b.emitter_simplify(const_provider, pc)?;
+                Ok((Shl(Box::new(sa.0), Box::new(sb.0)).try_simplify()?, sa.1 && sb.1))
             }
             Shr(box a, box b) => {
-                let sa = a.emitter_simplify(const_provider, pc);
-                let sb = b.emitter_simplify(const_provider, pc);
-                (Shr(Box::new(sa.0), Box::new(sb.0)).simplify(), sa.1 && sb.1)
+                let sa = a.emitter_simplify(const_provider, pc)?;
+                let sb = b.emitter_simplify(const_provider, pc)?;
+                Ok((Shr(Box::new(sa.0), Box::new(sb.0)).try_simplify()?, sa.1 && sb.1))
             }
             Ashr(box a, box b) => {
-                let sa = a.emitter_simplify(const_provider, pc);
-                let sb = b.emitter_simplify(const_provider, pc);
-                (Ashr(Box::new(sa.0), Box::new(sb.0)).simplify(), sa.1 && sb.1)
+                let sa = a.emitter_simplify(const_provider, pc)?;
+                let sb = b.emitter_simplify(const_provider, pc)?;
+                Ok((Ashr(Box::new(sa.0), Box::new(sb.0)).try_simplify()?, sa.1 && sb.1))
+            }
+
+            Eq(box a, box b) => {
+                let sa = a.emitter_simplify(const_provider, pc)?;
+                let sb = b.emitter_simplify(const_provider, pc)?;
+                Ok((Eq(Box::new(sa.0), Box::new(sb.0)).try_simplify()?, sa.1 && sb.1))
+            }
+            Ne(box a, box b) => {
+                let sa = a.emitter_simplify(const_provider, pc)?;
+                let sb = b.emitter_simplify(const_provider, pc)?;
+                Ok((Ne(Box::new(sa.0), Box::new(sb.0)).try_simplify()?, sa.1 && sb.1))
+            }
+            Lt(box a, box b) => {
+                let sa = a.emitter_simplify(const_provider, pc)?;
+                let sb = b.emitter_simplify(const_provider, pc)?;
+                Ok((Lt(Box::new(sa.0), Box::new(sb.0)).try_simplify()?, sa.1 && sb.1))
+            }
+            Le(box a, box b) => {
+                let sa = a.emitter_simplify(const_provider, pc)?;
+                let sb = b.emitter_simplify(const_provider, pc)?;
+                Ok((Le(Box::new(sa.0), Box::new(sb.0)).try_simplify()?, sa.1 && sb.1))
+            }
+            Gt(box a, box b) => {
+                let sa = a.emitter_simplify(const_provider, pc)?;
+                let sb = b.emitter_simplify(const_provider, pc)?;
+                Ok((Gt(Box::new(sa.0), Box::new(sb.0)).try_simplify()?, sa.1 && sb.1))
+            }
+            Ge(box a, box b) => {
+                let sa = a.emitter_simplify(const_provider, pc)?;
+                let sb = b.emitter_simplify(const_provider, pc)?;
+                Ok((Ge(Box::new(sa.0), Box::new(sb.0)).try_simplify()?, sa.1 && sb.1))
+            }
+            BitAnd(box a, box b) => {
+                let sa = a.emitter_simplify(const_provider, pc)?;
+                let sb = b.emitter_simplify(const_provider, pc)?;
+                Ok((BitAnd(Box::new(sa.0), Box::new(sb.0)).try_simplify()?, sa.1 && sb.1))
+            }
+            BitOr(box a, box b) => {
+                let sa = a.emitter_simplify(const_provider, pc)?;
+                let sb = b.emitter_simplify(const_provider, pc)?;
+                Ok((BitOr(Box::new(sa.0), Box::new(sb.0)).try_simplify()?, sa.1 && sb.1))
+            }
+            BitXor(box a, box b) => {
+                let sa = a.emitter_simplify(const_provider, pc)?;
+                let sb = b.emitter_simplify(const_provider, pc)?;
+                Ok((BitXor(Box::new(sa.0), Box::new(sb.0)).try_simplify()?, sa.1 && sb.1))
+            }
+            LogicalAnd(box a, box b) | LogicalOr(box a, box b) => {
+                let sa = a.emitter_simplify(const_provider, pc)?;
+                let sb = b.emitter_simplify(const_provider, pc)?;
+                let node = match self {
+                    LogicalAnd(..) => LogicalAnd(Box::new(sa.0), Box::new(sb.0)),
+                    _ => LogicalOr(Box::new(sa.0), Box::new(sb.0)),
+                }
+                .try_simplify()?;
+                // the right operand may be unresolved if it was short-circuited away
+                let succ = matches!(node, Integer(_)) || (sa.1 && sb.1);
+                Ok((node, succ))
+            }
+            Ternary(box c, box t, box e) => {
+                let sc = c.emitter_simplify(const_provider, pc)?;
+                match sc.0 {
+                    // only the chosen branch needs to resolve
+                    Integer(cv) => {
+                        if cv != 0 {
+                            t.emitter_simplify(const_provider, pc)
+                        } else {
+                            e.emitter_simplify(const_provider, pc)
+                        }
+                    }
+                    cnode => {
+                        let st = t.emitter_simplify(const_provider, pc)?;
+                        let se = e.emitter_simplify(const_provider, pc)?;
+                        Ok((
+                            Ternary(Box::new(cnode), Box::new(st.0), Box::new(se.0)),
+                            sc.1 && st.1 && se.1,
+                        ))
+                    }
+                }
+            }
+            Hi(box a) => {
+                let sa = a.emitter_simplify(const_provider, pc)?;
+                Ok((Hi(Box::new(sa.0)).try_simplify()?, sa.1))
+            }
+            Lo(box a) => {
+                let sa = a.emitter_simplify(const_provider, pc)?;
+                Ok((Lo(Box::new(sa.0)).try_simplify()?, sa.1))
+            }
+            PcrelHi(box a) => match a.emitter_simplify(const_provider, pc)? {
+                (Integer(sym), _) => Ok((Integer(hi_part(sym.wrapping_sub(pc))), true)),
+                (sa, _) => Ok((PcrelHi(Box::new(sa)), false)),
+            },
+            PcrelLo(box a) => match a.emitter_simplify(const_provider, pc)? {
+                (Integer(adr), _) => match const_provider.get_pcrel_hi(adr) {
+                    Some(v) => Ok((Integer(lo_part(v)), true)),
+                    None => Ok((PcrelLo(Box::new(Integer(adr))), false)),
+                },
+                (sa, _) => Ok((PcrelLo(Box::new(sa)), false)),
+            },
+            Function(name, args) if name == "sizeof" || name == "defined" => {
+                let value = match args.as_slice() {
+                    [Identifier(sym)] if name == "sizeof" => const_provider.get_size(sym),
+                    [Identifier(sym)] => const_provider.is_defined(sym).map(|d| d as u64),
+                    _ => None,
+                };
+                value.map_or_else(cloned_f, |v| Ok((Integer(v), true)))
+            }
+            Function(name, args) => {
+                let mut succ = true;
+                let mut sargs = Vec::new();
+                for arg in args.iter() {
+                    let s = arg.emitter_simplify(const_provider, pc)?;
+                    sargs.push(s.0);
+                    succ &= s.1;
+                }
+                Ok((Function(name.to_owned(), sargs).try_simplify()?, succ))
             }
 
             Argument(box node) => {
-                let s = node.emitter_simplify(const_provider, pc);
-                (Argument(Box::new(s.0)), s.1)
+                let s = node.emitter_simplify(const_provider, pc)?;
+                Ok((Argument(Box::new(s.0)), s.1))
+            }
+            Located(loc, box node) => {
+                let s = node.emitter_simplify(const_provider, pc)?;
+                Ok((Located(loc.clone(), Box::new(s.0)), s.1))
             }
+            // only meaningful to the macro expansion
+            KeywordArgument(..) | MacroDefinition(..) | MacroArgument(_) | Repeat(..)
+            | Iterate(..) => cloned_f(),
             Instruction(iname, args) => {
                 let mut succ = true;
                 let mut sargs = Vec::new();
                 for arg in args.iter() {
-                    let s = arg.emitter_simplify(const_provider, pc);
+                    let s = arg.emitter_simplify(const_provider, pc)?;
                     sargs.push(s.0);
                     succ &= s.1;
                 }
-                (Instruction(iname.to_owned(), sargs), succ)
+                Ok((Instruction(iname.to_owned(), sargs), succ))
             }
 
             Root(nodes) => {
                 let mut succ = true;
                 let mut snodes = Vec::new();
                 for node in nodes.iter() {
-                    let s = node.emitter_simplify(const_provider, pc);
+                    let s = node.emitter_simplify(const_provider, pc)?;
                     snodes.push(s.0);
                     succ &= s.1;
                 }
-                (Root(snodes), succ)
+                Ok((Root(snodes), succ))
             }
         }
     }
 }
 
+impl Node {
+    /// Direct child nodes, in order of appearance
+    pub fn children(&self) -> Vec<&Node> {
+        use Node::*;
+        match self {
+            Identifier(_) | Label(_) | Integer(_) | StringLiteral(_) | Register(_) | PcValue
+            | Omitted | MacroArgument(_) => vec![],
+            Negation(a) | Not(a) | LogicalNot(a) | Hi(a) | Lo(a) | PcrelHi(a) | PcrelLo(a)
+            | Argument(a) | Located(_, a) | KeywordArgument(_, a) => vec![a],
+            Plus(a, b)
+            | Minus(a, b)
+            | Times(a, b)
+            | Divide(a, b)
+            | Modulo(a, b)
+            | Shl(a, b)
+            | Shr(a, b)
+            | Ashr(a, b)
+            | Eq(a, b)
+            | Ne(a, b)
+            | Lt(a, b)
+            | Le(a, b)
+            | Gt(a, b)
+            | Ge(a, b)
+            | BitAnd(a, b)
+            | BitOr(a, b)
+            | BitXor(a, b)
+            | LogicalAnd(a, b)
+            | LogicalOr(a, b) => vec![a, b],
+            Ternary(c, t, e) => vec![c, t, e],
+            Function(_, nodes)
+            | Instruction(_, nodes)
+            | Root(nodes)
+            | MacroDefinition(_, _, nodes) => nodes.iter().collect(),
+            Repeat(count, body) => std::iter::once(count.as_ref()).chain(body.iter()).collect(),
+            Iterate(_, values, body) => values.iter().chain(body.iter()).collect(),
+        }
+    }
+
+    pub fn children_mut(&mut self) -> Vec<&mut Node> {
+        use Node::*;
+        match self {
+            Identifier(_) | Label(_) | Integer(_) | StringLiteral(_) | Register(_) | PcValue
+            | Omitted | MacroArgument(_) => vec![],
+            Negation(a) | Not(a) | LogicalNot(a) | Hi(a) | Lo(a) | PcrelHi(a) | PcrelLo(a)
+            | Argument(a) | Located(_, a) | KeywordArgument(_, a) => vec![a],
+            Plus(a, b)
+            | Minus(a, b)
+            | Times(a, b)
+            | Divide(a, b)
+            | Modulo(a, b)
+            | Shl(a, b)
+            | Shr(a, b)
+            | Ashr(a, b)
+            | Eq(a, b)
+            | Ne(a, b)
+            | Lt(a, b)
+            | Le(a, b)
+            | Gt(a, b)
+            | Ge(a, b)
+            | BitAnd(a, b)
+            | BitOr(a, b)
+            | BitXor(a, b)
+            | LogicalAnd(a, b)
+            | LogicalOr(a, b) => vec![a, b],
+            Ternary(c, t, e) => vec![c, t, e],
+            Function(_, nodes)
+            | Instruction(_, nodes)
+            | Root(nodes)
+            | MacroDefinition(_, _, nodes) => nodes.iter_mut().collect(),
+            Repeat(count, body) => std::iter::once(count.as_mut()).chain(body.iter_mut()).collect(),
+            Iterate(_, values, body) => values.iter_mut().chain(body.iter_mut()).collect(),
+        }
+    }
+
+    /// All identifiers and label references in the tree, in order of appearance
+    pub fn identifiers(&self) -> Vec<&str> {
+        match self {
+            Node::Identifier(name) | Node::Label(name) => vec![name],
+            _ => self
+                .children()
+                .into_iter()
+                .flat_map(|n| n.identifiers())
+                .collect(),
+        }
+    }
+}
+
 pub type ParseError = peg::error::ParseError<peg::str::LineCol>;
 
-pub fn ast_from_str(s: &str, spec: &arch::RiscVSpec) -> Result<Node, ParseError> {
-    grammar::top_level(s, spec)
+/// Errors from turning source text into the AST handed to the emitter
+#[derive(Debug, Clone)]
+pub enum SourceError {
+    Syntax(ParseError),
+    DuplicateMacro(String),
+    /// Macro name and the keyword argument that isn't one of its parameters
+    UnknownMacroParameter(String, String),
+    /// Macro name and the parameter with neither an argument nor a default
+    MissingMacroArgument(String, String),
+    TooManyMacroArguments(String),
+    /// Macro name and the vararg parameter used where a single value is needed
+    InvalidMacroArgument(String, String),
+    /// Macro that keeps expanding into itself
+    MacroRecursion(String),
+    /// `.rept` count that is negative or larger than the expansion limit
+    RepeatCountOutOfRange(i64),
+    /// Macros and repetitions expanding into more than the expansion limit
+    ExpansionTooLarge,
+    /// Symbol keeping the condition of `.if` or the count of `.rept` from being a constant,
+    /// if it isn't `$`
+    NotConstant(Option<String>),
+    InvalidCondition(EvalError),
+    /// Conditional directive with the wrong kind or number of arguments
+    InvalidConditionArguments(String),
+    /// `.elif`/`.else`/`.endif` outside of a conditional block
+    UnmatchedConditional(String),
+    /// `.elif`/`.else` following the `.else` of the same block
+    ConditionAfterElse(String),
+    /// `.if` without its `.endif`
+    UnterminatedConditional,
+    /// `.include` without a file name string
+    InvalidInclude,
+    /// Included file that is neither next to the including file nor in an include path
+    IncludeNotFound(String),
+    /// Included file that is already being included further up
+    RecursiveInclude(String),
+    /// File path and the reason it couldn't be read
+    Io(String, String),
+    /// Syntax error in an included file
+    InFile(Rc<str>, Box<SourceError>),
+    /// Error in the top-level element at the given location
+    Located(SourceLocation, Box<SourceError>),
+}
+
+impl SourceError {
+    pub fn located(self, loc: &SourceLocation) -> Self {
+        match self {
+            // errors in included files say where they are themselves
+            SourceError::Located(..) | SourceError::InFile(..) => self,
+            e => SourceError::Located(loc.clone(), Box::new(e)),
+        }
+    }
+}
+
+impl From<ParseError> for SourceError {
+    fn from(e: ParseError) -> Self {
+        SourceError::Syntax(e)
+    }
+}
+
+impl std::fmt::Display for SourceError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            SourceError::Syntax(e) => write!(f, "{}", e),
+            SourceError::DuplicateMacro(name) => write!(f, "duplicate macro '{}'", name),
+            SourceError::UnknownMacroParameter(name, param) => {
+                write!(f, "macro '{}' has no parameter '{}'", name, param)
+            }
+            SourceError::MissingMacroArgument(name, param) => {
+                write!(f, "missing argument '{}' of macro '{}'", param, name)
+            }
+            SourceError::TooManyMacroArguments(name) => {
+                write!(f, "too many arguments for macro '{}'", name)
+            }
+            SourceError::InvalidMacroArgument(name, param) => write!(
+                f,
+                "'\\{}' of macro '{}' doesn't hold a single value here",
+                param, name
+            ),
+            SourceError::MacroRecursion(name) => {
+                write!(f, "macro '{}' is expanded recursively too deep", name)
+            }
+            SourceError::RepeatCountOutOfRange(count) => write!(
+                f,
+                "'.rept' count {} is out of range 0..={}",
+                count,
+                crate::macros::MAX_EXPANSION_SIZE
+            ),
+            SourceError::ExpansionTooLarge => write!(
+                f,
+                "macros and repetitions expand to more than {} elements",
+                crate::macros::MAX_EXPANSION_SIZE
+            ),
+            SourceError::NotConstant(Some(name)) => {
+                write!(f, "'{}' has no constant value here", name)
+            }
+            SourceError::NotConstant(None) => write!(f, "expression is not a constant"),
+            SourceError::InvalidCondition(e) => write!(f, "invalid condition: {}", e),
+            SourceError::InvalidConditionArguments(name) => match name.as_str() {
+                ".ifdef" | ".ifndef" => write!(f, "'{}' needs a symbol name", name),
+                _ => write!(f, "'{}' needs a single condition", name),
+            },
+            SourceError::UnmatchedConditional(name) => {
+                write!(f, "'{}' without a matching '.if'", name)
+            }
+            SourceError::ConditionAfterElse(name) => write!(f, "'{}' after '.else'", name),
+            SourceError::UnterminatedConditional => write!(f, "'.if' without a matching '.endif'"),
+            SourceError::InvalidInclude => write!(f, "'.include' needs a file name string"),
+            SourceError::IncludeNotFound(name) => write!(f, "included file '{}' not found", name),
+            SourceError::RecursiveInclude(name) => {
+                write!(f, "'{}' is included from within itself", name)
+            }
+            SourceError::Io(path, e) => write!(f, "could not read '{}': {}", path, e),
+            SourceError::InFile(file, e) => write!(f, "{}: {}", file, e),
+            SourceError::Located(loc, e) => write!(f, "{}: {}", loc, e),
+        }
+    }
+}
+
+impl std::error::Error for SourceError {}
+
+fn is_numeric_label(name: &str) -> bool {
+    !name.is_empty() && name.bytes().all(|c| c.is_ascii_digit())
+}
+
+/// Splits a numeric label reference (`1b`, `2f`) into the label and whether it refers backward
+fn numeric_label_ref(name: &str) -> Option<(&str, bool)> {
+    let (num, backward) = match name.strip_suffix('b') {
+        Some(num) => (num, true),
+        None => (name.strip_suffix('f')?, false),
+    };
+    is_numeric_label(num).then_some((num, backward))
+}
+
+/// Value of `1010b` read as a binary literal, which it is unless numeric label `1010` was defined
+pub fn binary_literal(name: &str, label_defined: impl FnOnce(&str) -> bool) -> Option<u64> {
+    match numeric_label_ref(name) {
+        Some((num, true)) if !label_defined(num) => match Node::parse_integer(name) {
+            Ok(Node::Integer(v)) => Some(v),
+            _ => None,
+        },
+        _ => None,
+    }
+}
+
+/// Reads every `1010b` in an expression as a binary literal, for expressions outside of a source
+pub fn resolve_binary_literals(node: &mut Node) {
+    if let Node::Identifier(name) = node {
+        if let Some(v) = binary_literal(name, |_| false) {
+            *node = Node::Integer(v);
+        }
+    }
+    for child in node.children_mut() {
+        resolve_binary_literals(child);
+    }
+}
+
+/// Gives label references their final names: references to local labels (`.loop`) are
+/// qualified with the parent label in scope (`main.loop`, which also works from anywhere
+/// else), and each definition of a numeric label (`1:`) gets a unique name, with `1b`/`1f`
+/// pointing at the nearest previous/following definition. Before any `1010:`, `1010b` is
+/// the binary literal.
+pub fn resolve_labels(root: &mut Node) {
+    fn rename_refs(node: &mut Node, parent: &str, defined: &HashMap<String, usize>) {
+        if let Node::Identifier(name) = node {
+            if let Some(v) = binary_literal(name, |num| defined.contains_key(num)) {
+                *node = Node::Integer(v);
+            } else if let Some((num, backward)) = numeric_label_ref(name) {
+                let count = defined.get(num).copied().unwrap_or(0);
+                let instance = if backward { count } else { count + 1 };
+                *name = numeric_label_name(num, instance);
+            } else if name.starts_with('.') {
+                *name = format!("{}{}", parent, name);
+            }
+        }
+        for child in node.children_mut() {
+            rename_refs(child, parent, defined);
+        }
+    }
+
+    let mut parent = String::new();
+    let mut defined: HashMap<String, usize> = HashMap::new();
+    if let Node::Root(nodes) = root {
+        for node in nodes.iter_mut() {
+            let node = match node {
+                Node::Located(_, inner) => inner.as_mut(),
+                other => other,
+            };
+            match node {
+                Node::Label(name) if is_numeric_label(name) => {
+                    let count = defined.entry(name.clone()).or_insert(0);
+                    *count += 1;
+                    *name = numeric_label_name(name, *count);
+                }
+                // the emitter qualifies the definitions of local labels itself
+                Node::Label(name) if name.starts_with('.') => {}
+                Node::Label(name) => parent = name.clone(),
+                _ => rename_refs(node, &parent, &defined),
+            }
+        }
+    }
+}
+
+/// Fills in the line numbers and the file of the top-level elements' locations
+fn locate_lines(root: &mut Node, source: &str, file: Option<&Rc<str>>) {
+    fn locate(
+        nodes: &mut [Node],
+        source: &str,
+        file: Option<&Rc<str>>,
+        line: &mut usize,
+        scanned: &mut usize,
+    ) {
+        for node in nodes.iter_mut() {
+            if let Node::Located(loc, inner) = node {
+                *line += source[*scanned..loc.offset].matches('\n').count();
+                *scanned = loc.offset;
+                loc.line = *line;
+                loc.file = file.cloned();
+                // the bodies of macros and repetitions come right after their first line
+                if let Node::MacroDefinition(_, _, body)
+                | Node::Repeat(_, body)
+                | Node::Iterate(_, _, body) = inner.as_mut()
+                {
+                    locate(body, source, file, line, scanned);
+                }
+            }
+        }
+    }
+
+    if let Node::Root(nodes) = root {
+        let (mut line, mut scanned) = (1, 0);
+        locate(nodes, source, file, &mut line, &mut scanned);
+    }
+}
+
+/// Parses one source file (or string) into located top-level elements,
+/// syntax errors are attributed to `file`
+pub fn parse_source(
+    s: &str,
+    spec: &arch::RiscVSpec,
+    file: Option<&Rc<str>>,
+) -> Result<Node, SourceError> {
+    let mut ast = grammar::top_level(s, spec).map_err(|e| match file {
+        Some(file) => SourceError::InFile(file.clone(), Box::new(e.into())),
+        None => e.into(),
+    })?;
+    locate_lines(&mut ast, s, file);
+    Ok(ast)
+}
+
+/// Name of the n-th definition of a numeric label, can't clash with identifiers
+pub fn numeric_label_name(num: &str, instance: usize) -> String {
+    format!("{}#{}", num, instance)
+}
+
+/// Settings for reading sources beyond the main file
+#[derive(Debug, Clone, Default)]
+pub struct SourceOptions {
+    /// Directories searched by `.include` for files that aren't next to the including file
+    pub include_paths: Vec<PathBuf>,
+    /// Constants defined before the source, like with `.equ NAME, VALUE` (`-D NAME=VALUE`)
+    pub definitions: Vec<(String, u64)>,
+}
+
+pub fn ast_from_str(s: &str, spec: &arch::RiscVSpec) -> Result<Node, SourceError> {
+    ast_from_str_with_options(s, spec, &SourceOptions::default())
+}
+
+/// Parses a source string, `.include`s are resolved relative to the working directory
+pub fn ast_from_str_with_options(
+    s: &str,
+    spec: &arch::RiscVSpec,
+    options: &SourceOptions,
+) -> Result<Node, SourceError> {
+    let ast = parse_source(s, spec, None)?;
+    finish_ast(ast, spec, options, std::path::Path::new("."), vec![])
+}
+
+fn finish_ast(
+    ast: Node,
+    spec: &arch::RiscVSpec,
+    options: &SourceOptions,
+    dir: &std::path::Path,
+    including: Vec<PathBuf>,
+) -> Result<Node, SourceError> {
+    let mut ast = crate::macros::expand_macros(ast, spec, options, dir, including)?;
+    crate::include::read_binaries(&mut ast, options)?;
+    resolve_labels(&mut ast);
+    Ok(ast)
+}
+
+pub fn ast_from_file(path: &str, spec: &arch::RiscVSpec) -> Result<Node, SourceError> {
+    ast_from_file_with_options(path, spec, &SourceOptions::default())
 }
 
-pub fn ast_from_file(path: &str, spec: &arch::RiscVSpec) -> Result<Node, ParseError> {
+pub fn ast_from_file_with_options(
+    path: &str,
+    spec: &arch::RiscVSpec,
+    options: &SourceOptions,
+) -> Result<Node, SourceError> {
     use std::fs::File;
     use std::io::prelude::*;
     use std::io::BufReader;
@@ -156,5 +1020,9 @@ pub fn ast_from_file(path: &str, spec: &arch::RiscVSpec) -> Result<Node, ParseEr
     let mut buf = String::new();
     rd.read_to_string(&mut buf)
         .unwrap_or_else(|_| panic!("Could not read from source file {}", path));
-    ast_from_str(&buf, spec)
+    let path = std::path::Path::new(path);
+    let ast = parse_source(&buf, spec, Some(&Rc::from(path.to_string_lossy())))?;
+    let dir = path.parent().unwrap_or_else(|| std::path::Path::new("."));
+    let including = path.canonicalize().into_iter().collect();
+    finish_ast(ast, spec, options, dir, including)
 }
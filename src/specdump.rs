@@ -0,0 +1,302 @@
+use crate::arch::{FieldType, InstructionDefinition, InstructionFormat, RiscVSpec};
+use serde_json::json;
+
+#[derive(Debug, Copy, Clone)]
+pub enum DumpFormat {
+    Markdown,
+    Html,
+    Json,
+}
+
+impl std::str::FromStr for DumpFormat {
+    type Err = &'static str;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_ref() {
+            "markdown" | "md" => Ok(DumpFormat::Markdown),
+            "html" => Ok(DumpFormat::Html),
+            "json" => Ok(DumpFormat::Json),
+            _ => Err("Invalid dump format specified"),
+        }
+    }
+}
+
+/// A contiguous run of instruction bits [hi:lo] filled from one field's value bits [value_hi:value_lo]
+#[derive(Clone, Debug)]
+pub struct BitSegment {
+    pub hi: usize,
+    pub lo: usize,
+    /// Index into InstructionFormat.fields, None for bits not covered by any field
+    pub field: Option<usize>,
+    pub value_hi: usize,
+    pub value_lo: usize,
+}
+
+impl BitSegment {
+    fn width(&self) -> usize {
+        self.hi - self.lo + 1
+    }
+}
+
+/// Splits the instruction into bit segments from the MSB down, merging neighbouring bits
+/// that come from consecutive bits of the same field.
+pub fn bit_layout(fmt: &InstructionFormat) -> Vec<BitSegment> {
+    let mut segs: Vec<BitSegment> = Vec::new();
+    for bit in (0..fmt.ilen).rev() {
+        let mut owner = None;
+        'search: for (fi, fld) in fmt.fields.iter().enumerate() {
+            for e in fld.encoding.iter() {
+                let (first, last) = (e.instruction_first as usize, e.instruction_last() as usize);
+                if bit >= first && bit <= last {
+                    owner = Some((fi, e.value_first as usize + bit - first));
+                    break 'search;
+                }
+            }
+        }
+        if let Some(last) = segs.last_mut() {
+            let extends = match owner {
+                Some((fi, vbit)) => last.field == Some(fi) && last.value_lo == vbit + 1,
+                None => last.field.is_none(),
+            };
+            if extends {
+                last.lo = bit;
+                last.value_lo = owner.map_or(0, |o| o.1);
+                continue;
+            }
+        }
+        segs.push(BitSegment {
+            hi: bit,
+            lo: bit,
+            field: owner.map(|o| o.0),
+            value_hi: owner.map_or(0, |o| o.1),
+            value_lo: owner.map_or(0, |o| o.1),
+        });
+    }
+    segs
+}
+
+/// Text shown in a bit segment: binary digits for fixed fields, the field name otherwise
+fn segment_label(
+    insn: &InstructionDefinition,
+    fmt: &InstructionFormat,
+    seg: &BitSegment,
+) -> String {
+    let fi = match seg.field {
+        Some(fi) => fi,
+        None => return "?".repeat(seg.width()),
+    };
+    let fld = &fmt.fields[fi];
+    if let Some((_, val)) = insn.fields.iter().find(|(i, _)| *i == fi) {
+        let bits = (val >> seg.value_lo) & (u64::MAX >> (64 - seg.width()));
+        return format!("{:0width$b}", bits, width = seg.width());
+    }
+    if seg.value_lo == 0 && seg.value_hi + 1 == fld.length as usize {
+        fld.name.clone()
+    } else if seg.value_hi == seg.value_lo {
+        format!("{}[{}]", fld.name, seg.value_hi)
+    } else {
+        format!("{}[{}:{}]", fld.name, seg.value_hi, seg.value_lo)
+    }
+}
+
+fn bit_range(seg: &BitSegment) -> String {
+    if seg.hi == seg.lo {
+        format!("{}", seg.hi)
+    } else {
+        format!("{}..{}", seg.hi, seg.lo)
+    }
+}
+
+fn syntax(insn: &InstructionDefinition, fmt: &InstructionFormat) -> String {
+    let args: Vec<&str> = insn
+        .args
+        .iter()
+        .map(|a| fmt.fields[*a].name.as_ref())
+        .collect();
+    if args.is_empty() {
+        insn.name.clone()
+    } else {
+        format!("{} {}", insn.name, args.join(", "))
+    }
+}
+
+fn fixed_fields(insn: &InstructionDefinition, fmt: &InstructionFormat) -> Vec<String> {
+    insn.fields
+        .iter()
+        .map(|(fi, v)| format!("{} = {:#x}", fmt.fields[*fi].name, v))
+        .collect()
+}
+
+fn instructions_of<'spec>(
+    spec: &'spec RiscVSpec,
+    code: &'spec str,
+) -> impl Iterator<Item = &'spec InstructionDefinition> {
+    spec.get_all_instructions()
+        .iter()
+        .filter(move |i| i.extension == code)
+}
+
+pub fn dump_markdown(spec: &RiscVSpec) -> String {
+    let mut out = String::from("# Instruction reference\n");
+    for abi in spec.get_loaded_abis() {
+        out += &format!(
+            "\n## {} - {}\n\nBased on spec: {}\n",
+            abi.code, abi.name, abi.spec
+        );
+        for insn in instructions_of(spec, abi.code) {
+            let fmt = insn.get_format(spec);
+            let layout = bit_layout(fmt);
+            out += &format!("\n### `{}`\n\n", insn.name);
+            out += &format!("Syntax: `{}`  \n", syntax(insn, fmt));
+            out += &format!(
+                "Format: {} ({} bits), extension: {}\n\n",
+                fmt.name, fmt.ilen, insn.extension
+            );
+            let ranges: Vec<String> = layout.iter().map(bit_range).collect();
+            let labels: Vec<String> = layout.iter().map(|s| segment_label(insn, fmt, s)).collect();
+            out += &format!("| {} |\n", ranges.join(" | "));
+            out += &format!("|{}\n", "---|".repeat(layout.len()));
+            out += &format!("| {} |\n", labels.join(" | "));
+            let fixed = fixed_fields(insn, fmt);
+            if !fixed.is_empty() {
+                out += &format!("\nFixed fields: {}\n", fixed.join(", "));
+            }
+        }
+    }
+    out
+}
+
+fn html_escape(s: &str) -> String {
+    s.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+pub fn dump_html(spec: &RiscVSpec) -> String {
+    let mut out = String::from(
+        "<!DOCTYPE html>\n<html>\n<head><meta charset=\"utf-8\"><title>Instruction reference</title></head>\n<body>\n<h1>Instruction reference</h1>\n",
+    );
+    for abi in spec.get_loaded_abis() {
+        out += &format!(
+            "<h2>{} - {}</h2>\n<p>Based on spec: {}</p>\n",
+            html_escape(abi.code),
+            html_escape(abi.name),
+            html_escape(abi.spec)
+        );
+        for insn in instructions_of(spec, abi.code) {
+            let fmt = insn.get_format(spec);
+            let layout = bit_layout(fmt);
+            out += &format!(
+                "<h3 id=\"{0}\"><code>{0}</code></h3>\n",
+                html_escape(&insn.name)
+            );
+            out += &format!(
+                "<p>Syntax: <code>{}</code><br>Format: {} ({} bits), extension: {}</p>\n",
+                html_escape(&syntax(insn, fmt)),
+                html_escape(&fmt.name),
+                fmt.ilen,
+                html_escape(&insn.extension)
+            );
+            out += "<table border=\"1\">\n<tr>";
+            for seg in layout.iter() {
+                out += &format!("<th>{}</th>", bit_range(seg));
+            }
+            out += "</tr>\n<tr>";
+            for seg in layout.iter() {
+                out += &format!("<td>{}</td>", html_escape(&segment_label(insn, fmt, seg)));
+            }
+            out += "</tr>\n</table>\n";
+            let fixed = fixed_fields(insn, fmt);
+            if !fixed.is_empty() {
+                out += &format!("<p>Fixed fields: {}</p>\n", html_escape(&fixed.join(", ")));
+            }
+        }
+    }
+    out += "</body>\n</html>\n";
+    out
+}
+
+pub fn dump_json(spec: &RiscVSpec) -> String {
+    let extensions: Vec<_> = spec
+        .get_loaded_abis()
+        .iter()
+        .map(|abi| json!({ "code": abi.code, "name": abi.name, "spec": abi.spec }))
+        .collect();
+    let formats: Vec<_> = spec
+        .get_all_instruction_formats()
+        .iter()
+        .map(|fmt| {
+            let fields: Vec<_> = fmt
+                .fields
+                .iter()
+                .map(|fld| {
+                    let encoding: Vec<_> = fld
+                        .encoding
+                        .iter()
+                        .map(|e| json!([e.value_last, e.value_first, e.instruction_first]))
+                        .collect();
+                    json!({
+                        "name": fld.name,
+                        "type": match fld.vtype {
+                            FieldType::Register => "register",
+                            FieldType::Value => "value",
+                        },
+                        "length": fld.length,
+                        "encoding": encoding,
+                    })
+                })
+                .collect();
+            json!({ "name": fmt.name, "ilen": fmt.ilen, "fields": fields })
+        })
+        .collect();
+    let instructions: Vec<_> = spec
+        .get_all_instructions()
+        .iter()
+        .map(|insn| {
+            let fmt = insn.get_format(spec);
+            let args: Vec<_> = insn.args.iter().map(|a| &fmt.fields[*a].name).collect();
+            let fields: serde_json::Map<_, _> = insn
+                .fields
+                .iter()
+                .map(|(fi, v)| (fmt.fields[*fi].name.clone(), json!(v)))
+                .collect();
+            let layout: Vec<_> = bit_layout(fmt)
+                .iter()
+                .map(|seg| {
+                    json!({
+                        "hi": seg.hi,
+                        "lo": seg.lo,
+                        "field": seg.field.map(|fi| &fmt.fields[fi].name),
+                        "value_hi": seg.value_hi,
+                        "value_lo": seg.value_lo,
+                        "label": segment_label(insn, fmt, seg),
+                    })
+                })
+                .collect();
+            json!({
+                "name": insn.name,
+                "extension": insn.extension,
+                "format": fmt.name,
+                "ilen": fmt.ilen,
+                "args": args,
+                "fields": fields,
+                "layout": layout,
+            })
+        })
+        .collect();
+    let doc = json!({
+        "extensions": extensions,
+        "formats": formats,
+        "instructions": instructions,
+    });
+    serde_json::to_string_pretty(&doc).unwrap() + "\n"
+}
+
+pub fn dump(spec: &RiscVSpec, format: DumpFormat) -> String {
+    match format {
+        DumpFormat::Markdown => dump_markdown(spec),
+        DumpFormat::Html => dump_html(spec),
+        DumpFormat::Json => dump_json(spec),
+    }
+}
@@ -1,3 +1,44 @@
+/// The RV32I spec from `cfg/rv32i.toml`
+fn rv32i() -> crate::arch::RiscVSpec {
+    let mut rv = crate::arch::RiscVSpec::new();
+    rv.load_single_cfg_file(std::path::Path::new("./cfg/rv32i.toml"))
+        .unwrap();
+    rv
+}
+
+/// Assembles `src` into a flat binary with the RV32I spec
+fn assemble(src: &str) -> Result<Vec<u8>, crate::emit::flatbin::EmitError> {
+    assemble_with(&rv32i(), src)
+}
+
+/// Assembles `src` into a flat binary with the given spec
+fn assemble_with(
+    rv: &crate::arch::RiscVSpec,
+    src: &str,
+) -> Result<Vec<u8>, crate::emit::flatbin::EmitError> {
+    crate::emit::flatbin::emit_flat_binary(rv, &crate::parser::ast_from_str(src, rv).unwrap())
+}
+
+/// Assembles `src` with the RV32I spec and source options such as include paths and definitions
+fn assemble_with_options(
+    src: &str,
+    options: &crate::parser::SourceOptions,
+) -> Result<Vec<u8>, crate::emit::flatbin::EmitError> {
+    let rv = rv32i();
+    let ast = crate::parser::ast_from_str_with_options(src, &rv, options).unwrap();
+    crate::emit::flatbin::emit_flat_binary(&rv, &ast)
+}
+
+/// Assembles `src` with the RV32I spec and a section layout, returns the binary and the warnings
+fn assemble_with_layout(
+    src: &str,
+    layout: &crate::emit::section::LayoutOptions,
+) -> Result<(Vec<u8>, Vec<crate::emit::flatbin::EmitError>), crate::emit::flatbin::EmitError> {
+    let rv = rv32i();
+    let ast = crate::parser::ast_from_str(src, &rv).unwrap();
+    crate::emit::flatbin::emit_flat_binary_with_layout(&rv, &ast, layout)
+}
+
 #[test]
 fn test_parser_on_all_inputs() {
     use crate::parser::ast_from_file;
@@ -32,3 +73,1285 @@ fn test_parser_on_all_inputs() {
         }
     }
 }
+
+#[test]
+fn test_cfg_errors_are_located() {
+    let path = std::env::temp_dir().join("rvasm_test_cfg_errors_are_located.toml");
+    std::fs::write(
+        &path,
+        "[meta]\nname = \"x\"\ncode = \"Zx\"\nspec = \"s\"\n\n[instructions.foo]\nformat = \"Q\"\nargs = []\nfields = {}\n",
+    )
+    .unwrap();
+    let mut rv = crate::arch::RiscVSpec::new();
+    let err = rv.load_single_cfg_file(&path).unwrap_err();
+    std::fs::remove_file(&path).unwrap();
+    assert_eq!(err.key_path(), Some("instructions.foo.format"));
+    assert_eq!(
+        err.to_string(),
+        format!(
+            "{}:7: instructions.foo.format: unknown format 'Q'",
+            path.display()
+        )
+    );
+
+    std::fs::write(&path, "[meta]\nname = \"x\"\ncode = \n").unwrap();
+    let err = rv.load_single_cfg_file(&path).unwrap_err();
+    std::fs::remove_file(&path).unwrap();
+    assert!(err
+        .to_string()
+        .starts_with(&format!("{}:3: malformed TOML: ", path.display())));
+    assert!(!err.to_string().contains("at line"));
+
+    // errors between the files of an architecture are located at their `requires`
+    let dir = std::env::temp_dir().join("rvasm_test_cfg_errors_are_located");
+    let std_paths = vec![dir.clone()];
+    std::fs::create_dir_all(&dir).unwrap();
+    let meta = |code: &str, requires: &str| {
+        format!(
+            "[meta]\nname = \"x\"\ncode = \"{}\"\nspec = \"s\"\nrequires = [\"{}\"]\n",
+            code, requires
+        )
+    };
+    std::fs::write(dir.join("rv32i.toml"), meta("RV32I", "Zmissing")).unwrap();
+    let err = rv.load_arch_cfg(&std_paths, "RV32I", false).unwrap_err();
+    assert_eq!(
+        err.to_string(),
+        format!(
+            "{}:5: required spec 'Zmissing' not found",
+            dir.join("rv32i.toml").display()
+        )
+    );
+    std::fs::write(dir.join("rv32i.toml"), meta("RV32I", "Zcycle")).unwrap();
+    std::fs::write(dir.join("zcycle.toml"), meta("Zcycle", "RV32I")).unwrap();
+    let err = rv
+        .load_arch_cfg(&std_paths, "RV32IZcycle", false)
+        .unwrap_err();
+    std::fs::remove_dir_all(&dir).unwrap();
+    assert!(err.to_string().starts_with(&dir.display().to_string()));
+    assert!(err
+        .to_string()
+        .ends_with(".toml:5: dependency cycle between spec files"));
+}
+
+#[test]
+fn test_spec_dump() {
+    use crate::specdump::{bit_layout, dump, DumpFormat};
+
+    let mut rv = rv32i();
+
+    let markdown = dump(&rv, DumpFormat::Markdown);
+    assert!(markdown.starts_with(
+        "# Instruction reference\n\n\
+         ## RV32I - RISC-V 32-bits base integer ISA\n\n\
+         Based on spec: Unprivileged 20190608-Base-Ratified\n"
+    ));
+    assert!(markdown.contains(
+        "### `jal`\n\n\
+         Syntax: `jal rd, imm`  \n\
+         Format: J (32 bits), extension: RV32I\n\n\
+         | 31 | 30..21 | 20 | 19..12 | 11..7 | 6..0 |\n\
+         |---|---|---|---|---|---|\n\
+         | imm[20] | imm[10:1] | imm[11] | imm[19:12] | rd | 1101111 |\n\n\
+         Fixed fields: opcode = 0x6f\n"
+    ));
+
+    let html = dump(&rv, DumpFormat::Html);
+    assert!(html.starts_with("<!DOCTYPE html>\n"));
+    assert!(html.ends_with("</body>\n</html>\n"));
+    assert!(html.contains(
+        "<h3 id=\"sw\"><code>sw</code></h3>\n\
+         <p>Syntax: <code>sw rs2, imm, rs1</code><br>Format: S (32 bits), extension: RV32I</p>\n\
+         <table border=\"1\">\n\
+         <tr><th>31..25</th><th>24..20</th><th>19..15</th><th>14..12</th><th>11..7</th><th>6..0</th></tr>\n\
+         <tr><td>imm[11:5]</td><td>rs2</td><td>rs1</td><td>010</td><td>imm[4:0]</td><td>0100011</td></tr>\n\
+         </table>\n\
+         <p>Fixed fields: opcode = 0x23, funct3 = 0x2</p>\n"
+    ));
+
+    let json: serde_json::Value = serde_json::from_str(&dump(&rv, DumpFormat::Json)).unwrap();
+    assert_eq!(json["extensions"][0]["code"], "RV32I");
+    let sw = json["instructions"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .find(|i| i["name"] == "sw")
+        .unwrap();
+    assert_eq!(sw["format"], "S");
+    assert_eq!(sw["args"], serde_json::json!(["rs2", "imm", "rs1"]));
+    assert_eq!(
+        sw["fields"],
+        serde_json::json!({ "opcode": 0x23, "funct3": 2 })
+    );
+    assert_eq!(
+        sw["layout"][0],
+        serde_json::json!({
+            "hi": 31, "lo": 25, "field": "imm", "value_hi": 11, "value_lo": 5, "label": "imm[11:5]"
+        })
+    );
+
+    // bits no field covers are shown as unknown
+    rv.load_single_cfg_string(
+        "[meta]\nname = \"x\"\ncode = \"Zx\"\nspec = \"s\"\n\
+         [instruction_formats.G]\n\
+         op = { type = \"value\", length = 4, encoding = [[3,0,0]] }\n\
+         imm = { type = \"value\", length = 8, encoding = [[7,4,12], [3,0,8]] }\n\
+         [instructions.gap]\nformat = \"G\"\nargs = [\"imm\"]\nfields = { op = 5 }\n",
+    )
+    .unwrap();
+    let fmt = rv.get_instruction_by_name("gap").unwrap().get_format(&rv);
+    let layout: Vec<_> = bit_layout(fmt)
+        .iter()
+        .map(|s| (s.hi, s.lo, s.field))
+        .collect();
+    assert_eq!(layout, [(15, 8, Some(1)), (7, 4, None), (3, 0, Some(0))]);
+    assert!(dump(&rv, DumpFormat::Markdown).contains("| imm | ???? | 0101 |\n"));
+}
+
+#[test]
+fn test_opcodes_import_matches_toml() {
+    use crate::arch::RiscVSpec;
+
+    let toml_spec = rv32i();
+    let mut opc_spec = RiscVSpec::new();
+    let skipped = opc_spec
+        .load_opcodes_string(
+            "# comment\n\
+             add     rd rs1 rs2 31..25=0  14..12=0 6..2=0x0C 1..0=3\n\
+             sw      imm12hi rs1 rs2 imm12lo 14..12=2 6..2=0x08 1..0=3\n\
+             lw      rd rs1 imm12 14..12=2 6..2=0x00 1..0=3\n\
+             beq     bimm12hi rs1 rs2 bimm12lo 14..12=0 6..2=0x18 1..0=3\n\
+             jal     rd jimm20 6..2=0x1b 1..0=3\n\
+             $pseudo_op rv_i::jal j jimm20 11..7=0 6..2=0x1b 1..0=3\n\
+             $import rv_i::lui\n",
+            "I",
+        )
+        .unwrap();
+    assert_eq!(
+        skipped,
+        [
+            "line 7: skipped '$pseudo_op rv_i::jal'",
+            "line 8: skipped '$import rv_i::lui'"
+        ]
+    );
+    let encode = |spec: &RiscVSpec, name: &str, args: &[u64]| {
+        let mut bytes = [0u8; 4];
+        spec.get_instruction_by_name(name)
+            .unwrap()
+            .encode_into(&mut bytes, spec, args)
+            .unwrap();
+        bytes
+    };
+    assert_eq!(
+        encode(&toml_spec, "add", &[1, 2, 3]),
+        encode(&opc_spec, "add", &[1, 2, 3])
+    );
+    // memory accesses and branches take their arguments in assembler order, not encoding order
+    for (name, args) in [
+        ("sw", [3, 0x7f4, 2]),
+        ("lw", [1, 0x7f4, 2]),
+        ("beq", [1, 2, 0xffe]),
+    ]
+    .iter()
+    {
+        assert_eq!(
+            encode(&toml_spec, name, args),
+            encode(&opc_spec, name, args),
+            "{}",
+            name
+        );
+    }
+    assert_eq!(
+        encode(&toml_spec, "jal", &[1, 0x1ffffe]),
+        encode(&opc_spec, "jal", &[1, 0x1ffffe])
+    );
+    assert!(opc_spec.get_instruction_by_name("j").is_none());
+    let jal = opc_spec.get_instruction_by_name("jal").unwrap();
+    assert_eq!(jal.get_format(&opc_spec).fields[jal.args[1]].length, 21);
+
+    // a failed load leaves the spec as it was
+    let abis = opc_spec.get_loaded_abis().len();
+    let err = opc_spec
+        .load_opcodes_string(
+            "or rd rs1 rs2 31..25=0 14..12=6 6..2=0x0C 1..0=3\n\
+             foo imm12hi rs1 imm12lo 14..12=2 6..2=0x08 1..0=3\n",
+            "X",
+        )
+        .unwrap_err();
+    assert_eq!(
+        err.to_string(),
+        "foo: riscv-opcodes arguments 'imm12hi rs1 imm12lo' can't be put in assembler order"
+    );
+    assert!(opc_spec.get_instruction_by_name("or").is_none());
+    assert_eq!(opc_spec.get_loaded_abis().len(), abis);
+    assert!(opc_spec
+        .load_opcodes_string("add rd rs1 rs2 31..25=0 14..12=0 6..2=0x0C 1..0=3\n", "X")
+        .is_err());
+    assert!(opc_spec.get_instruction_by_name("add").is_some());
+}
+
+#[test]
+fn test_cfg_const_expressions() {
+    let mut rv = crate::arch::RiscVSpec::new();
+    rv.load_single_cfg_string(
+        "[meta]\nname = \"x\"\ncode = \"Zx\"\nspec = \"s\"\n\
+         [consts]\nXLEN = 64\nXLENB = \"XLEN / 8\"\nSHAMT = \"log2(XLEN)\"\n\
+         [instruction_formats.I]\n\
+         opcode = { type = \"value\", length = 7, encoding = [[6,0,0]] }\n\
+         imm = { type = \"value\", length = \"SHAMT\", encoding = [[\"SHAMT - 1\",0,20]] }\n",
+    )
+    .unwrap();
+    assert_eq!(rv.get_const("XLENB"), Some(8));
+    assert_eq!(rv.get_const("SHAMT"), Some(6));
+    let fmt = rv.get_instruction_format(0).unwrap();
+    assert_eq!(fmt.fields[1].length, 6);
+    assert_eq!(fmt.ilen, 26);
+
+    let err = rv
+        .load_single_cfg_string(
+            "[meta]\nname = \"y\"\ncode = \"Zy\"\nspec = \"s\"\n[consts]\nA = \"XLEN - FLEN\"\n",
+        )
+        .unwrap_err();
+    assert_eq!(err.to_string(), "consts.A: unknown const 'FLEN'");
+}
+
+#[test]
+fn test_cfg_when_guards() {
+    let ext = "[meta]\nname = \"x\"\ncode = \"Zx\"\nspec = \"s\"\n\
+               [instruction_formats.Ishift32]\nname = \"Ishift\"\nwhen = \"XLEN == 32\"\n\
+               opcode = { type = \"value\", length = 7, encoding = [[6,0,0]] }\n\
+               imm = { type = \"value\", length = 5, encoding = [[4,0,20]] }\n\
+               [instruction_formats.Ishift64]\nname = \"Ishift\"\nwhen = \"XLEN == 64\"\n\
+               opcode = { type = \"value\", length = 7, encoding = [[6,0,0]] }\n\
+               imm = { type = \"value\", length = 6, encoding = [[5,0,20]] }\n\
+               [instructions.c_jal]\nname = \"c.jal\"\nwhen = \"XLEN == 32\"\n\
+               format = \"Ishift\"\nargs = [\"imm\"]\nfields = { opcode = 0x6f }\n\
+               [instructions.myslli]\nformat = \"Ishift\"\nargs = [\"imm\"]\nfields = { opcode = 0x13 }\n";
+    for xlen in [32, 64].iter() {
+        let mut rv = crate::arch::RiscVSpec::new();
+        rv.load_single_cfg_string(&format!(
+            "[meta]\nname = \"b\"\ncode = \"RVb\"\nspec = \"s\"\n[consts]\nXLEN = {}\n",
+            xlen
+        ))
+        .unwrap();
+        rv.load_single_cfg_string(ext).unwrap();
+        let slli = rv.get_instruction_by_name("myslli").unwrap();
+        let imm_len = slli.get_format(&rv).fields[slli.args[0]].length;
+        assert_eq!(imm_len, if *xlen == 32 { 5 } else { 6 });
+        assert_eq!(rv.get_instruction_by_name("c.jal").is_some(), *xlen == 32);
+    }
+}
+
+#[test]
+fn test_cfg_format_inheritance() {
+    let meta = "[meta]\nname = \"x\"\ncode = \"Zx\"\nspec = \"s\"\n";
+    let mut rv = crate::arch::RiscVSpec::new();
+    rv.load_single_cfg_string(&format!(
+        "{}[fields]\n\
+         opcode = {{ type = \"value\", length = 7, encoding = [[6,0,0]] }}\n\
+         rd = {{ type = \"register\", length = 5, encoding = [[4,0,7]] }}\n\
+         [instruction_formats.Base]\n\
+         opcode = \"opcode\"\nrd = \"rd\"\n\
+         imm = {{ type = \"value\", length = 12, encoding = [[11,0,20]] }}\n\
+         [instruction_formats.Mid]\nextends = \"Base\"\n\
+         rs1 = {{ type = \"register\", length = 5, encoding = [[4,0,15]] }}\n\
+         [instruction_formats.Leaf]\nextends = \"Mid\"\nrd = false\n\
+         imm = {{ type = \"value\", length = 5, encoding = [[4,0,7]] }}\n",
+        meta
+    ))
+    .unwrap();
+    let fields = |name: &str| {
+        let fmt = rv
+            .get_all_instruction_formats()
+            .iter()
+            .find(|f| f.name == name)
+            .unwrap();
+        let names: Vec<_> = fmt
+            .fields
+            .iter()
+            .map(|f| (f.name.clone(), f.length))
+            .collect();
+        (names, fmt.ilen)
+    };
+    let field = |name: &str, length: i32| (name.to_owned(), length);
+    assert_eq!(
+        fields("Base"),
+        (
+            vec![field("opcode", 7), field("rd", 5), field("imm", 12)],
+            32
+        )
+    );
+    assert_eq!(
+        fields("Mid"),
+        (
+            vec![
+                field("opcode", 7),
+                field("rd", 5),
+                field("imm", 12),
+                field("rs1", 5)
+            ],
+            32
+        )
+    );
+    // `rd` is removed and `imm` replaced in place, so the format gets shorter
+    assert_eq!(
+        fields("Leaf"),
+        (
+            vec![field("opcode", 7), field("imm", 5), field("rs1", 5)],
+            20
+        )
+    );
+
+    let error = |src: &str| {
+        crate::arch::RiscVSpec::new()
+            .load_single_cfg_string(&format!("{}{}", meta, src))
+            .unwrap_err()
+            .to_string()
+    };
+    assert_eq!(
+        error("[instruction_formats.A]\nrd = \"nope\"\n"),
+        "instruction_formats.A.rd: unknown field 'nope'"
+    );
+    assert_eq!(
+        error("[instruction_formats.A]\nextends = \"Missing\"\n"),
+        "instruction_formats.A.extends: unknown format 'Missing'"
+    );
+    // a base has to be defined before the formats extending it, so cycles can't be built
+    assert_eq!(
+        error(
+            "[instruction_formats.A]\nextends = \"B\"\n\
+             [instruction_formats.B]\nextends = \"A\"\n"
+        ),
+        "instruction_formats.A.extends: unknown format 'B'"
+    );
+}
+
+#[test]
+fn test_long_instructions() {
+    let mut rv = rv32i();
+    rv.load_single_cfg_string(
+        "[meta]\nname = \"x\"\ncode = \"Xlong\"\nspec = \"s\"\n[consts]\nILEN = 64\n\
+         [instruction_formats.L48]\n\
+         opcode = { type = \"value\", length = 7, encoding = [[6,0,0]] }\n\
+         rd = \"rd\"\n\
+         imm = { type = \"value\", length = 32, encoding = [[31,0,16]] }\n\
+         [instruction_formats.L64]\nextends = \"L48\"\n\
+         imm = { type = \"value\", length = 48, encoding = [[47,0,16]] }\n\
+         [instructions.li48]\nformat = \"L48\"\nargs = [\"rd\", \"imm\"]\nfields = { opcode = 0b0011111 }\n\
+         [instructions.li64]\nformat = \"L64\"\nargs = [\"rd\", \"imm\"]\nfields = { opcode = 0b0111111 }\n",
+    )
+    .unwrap();
+    let bin = assemble_with(
+        &rv,
+        "li48 x1, 0x89abcdef\nli64 x2, 0x123456789abc\nadd x0, x0, x0\n",
+    )
+    .unwrap();
+    assert_eq!(
+        bin,
+        vec![
+            0x9f, 0x00, 0xef, 0xcd, 0xab, 0x89, // li48, padded to IALIGN
+            0x00, 0x00, //
+            0x3f, 0x01, 0xbc, 0x9a, 0x78, 0x56, 0x34, 0x12, // li64
+            0x33, 0x00, 0x00, 0x00, // add
+        ]
+    );
+    assert_eq!(crate::arch::encoded_instruction_length(&bin[0..]), Some(6));
+    assert_eq!(crate::arch::encoded_instruction_length(&bin[8..]), Some(8));
+    assert_eq!(crate::arch::encoded_instruction_length(&bin[16..]), Some(4));
+
+    let err = rv
+        .load_single_cfg_string(
+            "[meta]\nname = \"y\"\ncode = \"Xbad\"\nspec = \"s\"\n\
+             [instructions.bad48]\nformat = \"L48\"\nargs = [\"rd\", \"imm\"]\nfields = { opcode = 0b0110011 }\n",
+        )
+        .unwrap_err();
+    assert_eq!(
+        err.to_string(),
+        "instructions.bad48: opcode bits encode a 32-bit length for a 48-bit format"
+    );
+}
+
+#[test]
+fn test_expression_operators() {
+    use crate::parser::Node;
+
+    let rv = crate::arch::RiscVSpec::new();
+    let cases: &[(&str, u64)] = &[
+        ("(1 << 3) | (1 << 7)", 0x88),
+        ("0xff & ~0x0f", 0xf0),
+        ("0b1100 ^ 0b1010", 0b0110),
+        ("17 % 5", 2),
+        ("1 + 2 * 3", 7),
+        ("-1 + 2", 1),
+        ("1 << 2 + 1", 8),
+        ("1 | 2 == 2", 1),
+        ("3 & 1 ^ 1 | 4", 4),
+        ("1 < 2 && 2 < 1", 0),
+        ("0 || !0", 1),
+        ("!5", 0),
+        ("1 ? 2 : 3", 2),
+        ("0 ? 2 : 1 ? 4 : 5", 4),
+        ("2 > 1 ? 10 : 20", 10),
+    ];
+    for (src, val) in cases.iter() {
+        match crate::grammar::expression(src, &rv) {
+            Ok(Node::Integer(v)) => assert_eq!(v, *val, "{}", src),
+            other => panic!("{} evaluated to {:?}", src, other),
+        }
+    }
+    // short-circuiting doesn't need the other operand
+    let node = crate::grammar::expression("0 && UNDEFINED", &rv).unwrap();
+    assert!(matches!(
+        node.emitter_simplify(&|_: &str| None, 0),
+        Ok((Node::Integer(0), true))
+    ));
+}
+
+#[test]
+fn test_signed_arithmetic() {
+    use crate::emit::flatbin::EmitError;
+    use crate::parser::{EvalError, Node};
+
+    let rv = crate::arch::RiscVSpec::new();
+    let eval = |src: &str| crate::grammar::expression(src, &rv).unwrap().try_simplify();
+    let cases: &[(&str, i64)] = &[
+        ("-8 / 2", -4),
+        ("-7 % 2", -1),
+        ("7 % -2", 1),
+        ("-1 < 0", 1),
+        ("-16 >>> 2", -4),
+        ("-16 >> 60", 15),
+        ("udiv(-8, 2)", i64::MAX - 3),
+        ("urem(-1, 16)", 15),
+        ("ult(-1, 0)", 0),
+        ("uge(-1, 0)", 1),
+        // wraps without overflow when read as signed or as unsigned
+        ("0xFFFFFFFFFFFFFFFF + 1", 0),
+        ("0x7FFFFFFFFFFFFFFF + 1", i64::MIN),
+        ("-0x8000000000000000 - 1 + 1", i64::MIN),
+        ("-1 << 4", -16),
+        ("1 << 63", i64::MIN),
+    ];
+    for (src, val) in cases.iter() {
+        match eval(src) {
+            Ok(Node::Integer(v)) => assert_eq!(v as i64, *val, "{}", src),
+            other => panic!("{} evaluated to {:?}", src, other),
+        }
+    }
+    let errors = [
+        ("1 / 0", EvalError::DivisionByZero),
+        ("1 % (2 - 2)", EvalError::DivisionByZero),
+        ("urem(1, 0)", EvalError::DivisionByZero),
+        ("1 << 64", EvalError::ShiftOutOfRange(64)),
+        ("1 >> -1", EvalError::ShiftOutOfRange(u64::MAX)),
+        ("3 << 63", EvalError::Overflow("<<", 3, 63)),
+        (
+            "0x4000000000000000 << 2",
+            EvalError::Overflow("<<", 1 << 62, 2),
+        ),
+        (
+            "0x8000000000000000 * 4",
+            EvalError::Overflow("*", 1 << 63, 4),
+        ),
+        (
+            "-0x8000000000000000 / -1",
+            EvalError::Overflow("/", 1 << 63, u64::MAX),
+        ),
+        ("log2(0)", EvalError::InvalidArgument("log2(0)".to_owned())),
+    ];
+    for (src, err) in errors.iter() {
+        assert_eq!(eval(src).unwrap_err(), *err, "{}", src);
+    }
+
+    assert!(matches!(
+        assemble("addi a0, a0, 1 / (N - N)\n.equ N, 0\n"),
+        Err(EmitError::Located(
+            loc,
+            box EmitError::InvalidExpression(iname, EvalError::DivisionByZero)
+        )) if iname == "addi" && loc.line == 1
+    ));
+}
+
+#[test]
+fn test_relocation_operators() {
+    // data is a forward reference, so all of these go through the deferred path
+    let with_relocs = assemble(
+        ".org 0x100\nstart:\n\
+         lui a0, %hi(data)\naddi a0, a0, %lo(data)\n\
+         .Lpc: auipc a1, %pcrel_hi(data)\naddi a1, a1, %pcrel_lo(.Lpc)\n\
+         .org 0x1800\ndata:\n",
+    )
+    .unwrap();
+    let explicit = assemble(
+        ".org 0x100\n\
+         lui a0, 0x2000\naddi a0, a0, -0x800\n\
+         auipc a1, 0x1000\naddi a1, a1, 0x6f8\n\
+         .org 0x1800\n",
+    )
+    .unwrap();
+    assert_eq!(with_relocs, explicit);
+}
+
+#[test]
+fn test_integer_literals() {
+    use crate::parser::Node;
+
+    let rv = crate::arch::RiscVSpec::new();
+    let cases: &[(&str, u64)] = &[
+        ("1_000", 1000),
+        ("0X1F", 0x1f),
+        ("0B1010", 10),
+        ("0o1_7", 0o17),
+        ("0d09", 9),
+        ("0BEh", 0xbe),
+        ("1_0Fh", 0x10f),
+        ("1010b", 10),
+        ("17o", 0o17),
+        ("17Q", 0o17),
+        ("99d", 99),
+        ("0x1b", 0x1b),
+        ("0b", 0),
+        ("0xFFFF_FFFF_FFFF_FFFF", u64::MAX),
+    ];
+    for (src, val) in cases.iter() {
+        match crate::grammar::expression(src, &rv) {
+            Ok(Node::Integer(v)) => assert_eq!(v, *val, "{}", src),
+            other => panic!("{} parsed to {:?}", src, other),
+        }
+    }
+    let errors: &[(&str, &str)] = &[
+        (
+            "0x1_0000_0000_0000_0000",
+            "integer literal that fits in 64 bits",
+        ),
+        ("0x___", "integer literal with valid digits"),
+        ("0b102", "integer literal with valid digits"),
+        ("12a", "integer literal with valid digits"),
+    ];
+    for (src, msg) in errors.iter() {
+        let err = match crate::parser::ast_from_str(&format!("nop\naddi a0, a0, {}\n", src), &rv) {
+            Err(crate::parser::SourceError::Syntax(err)) => err,
+            other => panic!("{}: {:?}", src, other),
+        };
+        assert_eq!(err.location.line, 2, "{}", src);
+        assert!(err.expected.tokens().any(|t| t == *msg), "{}: {}", src, err);
+    }
+}
+
+#[test]
+fn test_builtin_functions() {
+    use crate::parser::{EvalError, Node};
+
+    let rv = crate::arch::RiscVSpec::new();
+    let eval = |src: &str| crate::grammar::expression(src, &rv).unwrap().try_simplify();
+    let cases: &[(&str, i64)] = &[
+        ("align(5, 4)", 8),
+        ("align(8, 4)", 8),
+        ("align(0x1001, 0x1000)", 0x2000),
+        ("log2(4096)", 12),
+        ("min(-1, 3)", -1),
+        ("max(-1, 3)", 3),
+        ("sext(0x800, 12)", -0x800),
+        ("sext(0x7ff, 12)", 0x7ff),
+        ("zext(-1, 12)", 0xfff),
+        ("bits(0x12345678, 15, 8)", 0x56),
+        ("bits(-1, 63, 63)", 1),
+    ];
+    for (src, val) in cases.iter() {
+        match eval(src) {
+            Ok(Node::Integer(v)) => assert_eq!(v as i64, *val, "{}", src),
+            other => panic!("{} evaluated to {:?}", src, other),
+        }
+    }
+    assert_eq!(
+        eval("bits(1, 3, 4)").unwrap_err(),
+        EvalError::InvalidArgument("bits(1, 3, 4)".to_owned())
+    );
+    assert_eq!(
+        eval("align(3, 0)").unwrap_err(),
+        EvalError::InvalidArgument("align(3, 0)".to_owned())
+    );
+    let parse_error = |src: &str| {
+        crate::grammar::expression(src, &rv)
+            .unwrap_err()
+            .to_string()
+    };
+    assert_eq!(
+        parse_error("min(1)"),
+        "error at 1:7: expected 2 arguments for 'min'"
+    );
+    assert_eq!(
+        parse_error("sizeof(a, b)"),
+        "error at 1:13: expected 1 argument for 'sizeof'"
+    );
+    assert_eq!(
+        parse_error("sizeof(1)"),
+        "error at 1:10: expected symbol name"
+    );
+    assert_eq!(
+        parse_error("foo(1)"),
+        "error at 1:7: expected known function"
+    );
+
+    assert_eq!(
+        assemble(
+            "addi a0, zero, sizeof(table)\n\
+             addi a1, zero, defined(table) + 2 * defined(missing)\n\
+             addi a2, zero, sizeof(tail)\n\
+             table: addi x0, x0, 0\naddi x0, x0, 0\n\
+             tail: addi x0, x0, 0\n"
+        )
+        .unwrap(),
+        assemble(
+            "addi a0, zero, 8\naddi a1, zero, 1\naddi a2, zero, 4\n\
+             addi x0, x0, 0\naddi x0, x0, 0\naddi x0, x0, 0\n"
+        )
+        .unwrap()
+    );
+}
+
+#[test]
+fn test_numeric_labels() {
+    assert_eq!(
+        assemble(
+            "1: addi a0, zero, 1f\n\
+             addi a1, zero, 1b\n\
+             1: addi a2, zero, 1b\n\
+             global: addi a3, zero, 1b\n\
+             addi a4, zero, 2f - 1b + 0b1 + 1010b\n\
+             2:\n"
+        )
+        .unwrap(),
+        assemble(
+            "addi a0, zero, 8\naddi a1, zero, 0\naddi a2, zero, 8\n\
+             addi a3, zero, 8\naddi a4, zero, 23\n"
+        )
+        .unwrap()
+    );
+    // `10b` is binary until label 10 is defined
+    assert_eq!(
+        assemble(
+            "addi a0, zero, 10b\n\
+             .if 11b == 3\naddi a1, zero, 11b\n.endif\n\
+             10: addi a2, zero, 10b\n"
+        )
+        .unwrap(),
+        assemble("addi a0, zero, 2\naddi a1, zero, 3\naddi a2, zero, 8\n").unwrap()
+    );
+    // no definition in that direction
+    assert!(assemble("1: addi a0, zero, 1f\n").is_err());
+    assert!(assemble("addi a0, zero, 2b\n").is_err());
+    // a multi-byte character isn't split when looking for a `b`/`f` suffix
+    assert!(assemble(".irpc c, é\n.endr\n").is_ok());
+}
+
+#[test]
+fn test_local_label_scopes() {
+    assert_eq!(
+        assemble(
+            "main: addi a0, zero, .end\n\
+             addi a1, zero, other.end\n\
+             .end: addi a2, zero, main.end\n\
+             other: addi a3, zero, .end\n\
+             .end: addi a4, zero, main.end\n"
+        )
+        .unwrap(),
+        assemble(
+            "addi a0, zero, 8\naddi a1, zero, 16\naddi a2, zero, 8\n\
+             addi a3, zero, 16\naddi a4, zero, 8\n"
+        )
+        .unwrap()
+    );
+    let err = assemble("main: addi a0, zero, 0\n\nfoo: addi a0, zero, .missing\n").unwrap_err();
+    assert_eq!(err.to_string(), "line 3: undefined symbol 'foo.missing'");
+}
+
+#[test]
+fn test_undefined_symbols() {
+    use crate::emit::flatbin::EmitError;
+
+    let err = assemble(
+        "start: addi a0, zero, buffer_size\n\
+         .equ BUFFER_SIZE, 64\n\
+         jal ra, strat\n\
+         addi a1, zero, 1f + .lop + XLEM\n\
+         .loop:\n",
+    )
+    .unwrap_err();
+    let refs = match &err {
+        EmitError::UndefinedSymbol(refs) => refs,
+        other => panic!("{:?}", other),
+    };
+    let found: Vec<(&str, usize, Option<&str>)> = refs
+        .iter()
+        .map(|r| {
+            (
+                r.name.as_ref(),
+                r.location.as_ref().unwrap().line,
+                r.suggestion.as_deref(),
+            )
+        })
+        .collect();
+    assert_eq!(
+        found,
+        vec![
+            ("buffer_size", 1, Some("BUFFER_SIZE")),
+            ("strat", 3, Some("start")),
+            ("1f", 4, None),
+            ("start.lop", 4, Some("start.loop")),
+            ("XLEM", 4, Some("XLEN")),
+        ]
+    );
+    assert!(err
+        .to_string()
+        .contains("line 3: undefined symbol 'strat', did you mean 'start'?"));
+
+    // in source order across included files and sections
+    let err = assemble(
+        ".data\n.include \"test/include/lib/undefined.s\"\n.text\naddi a2, zero, second\n",
+    )
+    .unwrap_err();
+    let names: Vec<&str> = match &err {
+        EmitError::UndefinedSymbol(refs) => refs.iter().map(|r| r.name.as_ref()).collect(),
+        other => panic!("{:?}", other),
+    };
+    assert_eq!(names, ["first", "second"]);
+}
+
+#[test]
+fn test_lazy_and_reassignable_constants() {
+    assert_eq!(
+        assemble(
+            ".equ WORDS, SIZE / 4\n\
+             .equ SIZE, end - start\n\
+             start: addi a0, zero, SIZE\n\
+             addi a1, zero, WORDS\n\
+             end:\n\
+             COUNT = 1\n\
+             addi a2, zero, COUNT\n\
+             COUNT = COUNT + 1\n\
+             .set COUNT, COUNT * 10\n\
+             addi a3, zero, COUNT\n"
+        )
+        .unwrap(),
+        assemble("addi a0, zero, 8\naddi a1, zero, 2\naddi a2, zero, 1\naddi a3, zero, 20\n")
+            .unwrap()
+    );
+    // a pending .equ keeps the value a .set constant had at its definition
+    assert_eq!(
+        assemble("X = 1\n.equ A, X + fwd\nX = 2\nfwd:\naddi a1, zero, A\n").unwrap(),
+        assemble("addi a1, zero, 1\n").unwrap()
+    );
+    let error = |src: &str| assemble(src).unwrap_err().to_string();
+    assert_eq!(
+        error("addi a0, zero, B\n.equ A, B + 1\n.equ B, A\n"),
+        "line 2: constant 'A' is defined in terms of itself"
+    );
+    assert_eq!(
+        error(".equ A, nothere\naddi a0, zero, A\n"),
+        "line 1: undefined symbol 'nothere'"
+    );
+    assert_eq!(
+        error(".equ X, 1\nX = 2\n"),
+        "line 2: duplicate constant 'X'"
+    );
+    assert_eq!(
+        error(".set Y, later\nlater:\n"),
+        "line 1: '.set' needs the value of 'later' before it is defined"
+    );
+}
+
+#[test]
+fn test_macros() {
+    use crate::parser::ast_from_str;
+
+    let rv = rv32i();
+    assert_eq!(
+        assemble(
+            ".macro load rd, val=5\n\
+             addi \\rd, zero, \\val\n\
+             .endm\n\
+             .macro apply op, args:vararg\n\
+             \\op \\args\n\
+             .endm\n\
+             .macro countdown reg\n\
+             again\\@: addi \\reg, \\reg, -1\n\
+             bne \\reg, zero, again\\@\n\
+             1: beq \\reg, zero, 1b\n\
+             .endm\n\
+             load a0\n\
+             load a1, 7\n\
+             load val=3, rd=a2\n\
+             apply add, a0, a1, a2\n\
+             countdown a0\n\
+             countdown a1\n"
+        )
+        .unwrap(),
+        assemble(
+            "addi a0, zero, 5\naddi a1, zero, 7\naddi a2, zero, 3\nadd a0, a1, a2\n\
+             l0: addi a0, a0, -1\nbne a0, zero, l0\nl1: beq a0, zero, l1\n\
+             l2: addi a1, a1, -1\nbne a1, zero, l2\nl3: beq a1, zero, l3\n"
+        )
+        .unwrap()
+    );
+    let error = |src: &str| ast_from_str(src, &rv).unwrap_err().to_string();
+    let def = ".macro m a\naddi a0, a0, \\a\n.endm\n";
+    assert_eq!(
+        error(&format!("{}m\n", def)),
+        "line 4: missing argument 'a' of macro 'm'"
+    );
+    assert_eq!(
+        error(&format!("{}m 1, 2\n", def)),
+        "line 4: too many arguments for macro 'm'"
+    );
+    assert_eq!(
+        error(&format!("{}m b=1\n", def)),
+        "line 4: macro 'm' has no parameter 'b'"
+    );
+    assert_eq!(
+        error(&format!("{}{}", def, def)),
+        "line 4: duplicate macro 'm'"
+    );
+    assert_eq!(
+        error(".macro r\nr\n.endm\naddi x0, x0, 0\nr\n"),
+        "line 5: macro 'r' is expanded recursively too deep"
+    );
+}
+
+#[test]
+fn test_includes() {
+    use crate::emit::flatbin::emit_flat_binary;
+    use crate::parser::{ast_from_file_with_options, SourceOptions};
+
+    let rv = rv32i();
+    let options = SourceOptions {
+        include_paths: vec!["./test/include/lib".into()],
+        ..Default::default()
+    };
+    let ast = ast_from_file_with_options("./test/include/main.s", &rv, &options).unwrap();
+    assert_eq!(
+        emit_flat_binary(&rv, &ast).unwrap(),
+        assemble("addi a0, zero, 3\nclear: addi a1, zero, 0\njalr zero, ra, 0\njal ra, clear\n")
+            .unwrap()
+    );
+    let error = |path: &str| {
+        ast_from_file_with_options(path, &rv, &options)
+            .unwrap_err()
+            .to_string()
+    };
+    assert_eq!(
+        error("./test/include/cycle_a.s"),
+        "./test/include/cycle_b.s:2: 'cycle_a.s' is included from within itself"
+    );
+    assert!(error("./test/include/bad.s").starts_with("./test/include/lib/broken.s: error at 2:15"));
+    assert_eq!(
+        ast_from_file_with_options("./test/include/main.s", &rv, &SourceOptions::default())
+            .unwrap_err()
+            .to_string(),
+        "./test/include/main.s:4: included file 'util.s' not found"
+    );
+
+    // includes are read where they are expanded, only in the taken branches of conditionals
+    assert_eq!(
+        assemble_with_options(
+            ".ifdef NOPE\n.include \"missing.s\"\n.endif\n\
+             .macro defs\n.include \"test/include/defs.s\"\n.endm\n\
+             defs\nzero_reg a0\n\
+             .rept 2\n.include \"nop.s\"\n.endr\n",
+            &options
+        )
+        .unwrap(),
+        assemble("addi a0, zero, 0\naddi x0, x0, 0\naddi x0, x0, 0\n").unwrap()
+    );
+}
+
+#[test]
+fn test_conditional_assembly() {
+    use crate::parser::{ast_from_str, SourceOptions};
+
+    let rv = rv32i();
+    let src = ".equ RAM, 0x100\n\
+               .ifdef BOARD\n\
+               addi a0, zero, BOARD\n\
+               .elif XLEN == 64\n\
+               addi a0, zero, 64\n\
+               .else\n\
+               addi a0, zero, RAM / 16\n\
+               .if defined(RAM) && RAM > 0x80\n\
+               addi a1, zero, 1\n\
+               .endif\n\
+               .endif\n\
+               .macro pick n\n\
+               .if \\n == 1\n\
+               addi a2, zero, 11\n\
+               .elif \\n == 2\n\
+               addi a2, zero, 22\n\
+               .endif\n\
+               .endm\n\
+               pick 2\n";
+    assert_eq!(
+        assemble(src).unwrap(),
+        assemble("addi a0, zero, 16\naddi a1, zero, 1\naddi a2, zero, 22\n").unwrap()
+    );
+    let options = SourceOptions {
+        definitions: vec![("BOARD".to_owned(), 7)],
+        ..Default::default()
+    };
+    assert_eq!(
+        assemble_with_options(src, &options).unwrap(),
+        assemble("addi a0, zero, 7\naddi a2, zero, 22\n").unwrap()
+    );
+
+    let error = |src: &str| ast_from_str(src, &rv).unwrap_err().to_string();
+    assert_eq!(
+        error("start:\n.if start\n.endif\n"),
+        "line 2: 'start' has no constant value here"
+    );
+    assert_eq!(
+        error("addi x0, x0, 0\n.if 1\n"),
+        "line 2: '.if' without a matching '.endif'"
+    );
+    assert_eq!(
+        error(".endif\n"),
+        "line 1: '.endif' without a matching '.if'"
+    );
+    assert_eq!(
+        error(".if 0\n.else\n.elif 1\n.endif\n"),
+        "line 3: '.elif' after '.else'"
+    );
+    assert_eq!(
+        error(".if 1 / 0\n.endif\n"),
+        "line 1: invalid condition: division by zero"
+    );
+}
+
+#[test]
+fn test_repetitions() {
+    use crate::parser::ast_from_str;
+
+    let rv = rv32i();
+    assert_eq!(
+        assemble(
+            ".equ N, 2\n\
+             .rept N + 1\n\
+             addi a0, a0, 1\n\
+             .endr\n\
+             .irpc n, 12\n\
+             addi a1, a1, \\n\n\
+             .endr\n\
+             .macro clear regs:vararg\n\
+             .irp r, \\regs\n\
+             addi \\r, zero, 0\n\
+             .endr\n\
+             .endm\n\
+             clear a2, a3\n\
+             .rept 2\n\
+             .irp name, one, two\n\
+             \\name\\()_\\@: jal zero, \\name\\()_\\@\n\
+             .endr\n\
+             .endr\n\
+             .rept 0\n\
+             addi a0, a0, 1\n\
+             .endr\n"
+        )
+        .unwrap(),
+        assemble(
+            "addi a0, a0, 1\naddi a0, a0, 1\naddi a0, a0, 1\n\
+             addi a1, a1, 1\naddi a1, a1, 2\n\
+             addi a2, zero, 0\naddi a3, zero, 0\n\
+             l0: jal zero, l0\nl1: jal zero, l1\nl2: jal zero, l2\nl3: jal zero, l3\n"
+        )
+        .unwrap()
+    );
+    assert_eq!(
+        ast_from_str("addi x0, x0, 0\n.rept later\n.endr\nlater:\n", &rv)
+            .unwrap_err()
+            .to_string(),
+        "line 2: 'later' has no constant value here"
+    );
+    let error = |src: &str| ast_from_str(src, &rv).unwrap_err().to_string();
+    // negative counts aren't taken for 0, nor huge ones left to run until the memory runs out
+    assert_eq!(
+        error(".rept -1\n.endr\n"),
+        "line 1: '.rept' count -1 is out of range 0..=1048576"
+    );
+    assert_eq!(
+        error(".rept 0xffffffffffff\naddi x0, x0, 0\n.endr\n"),
+        "line 1: '.rept' count 281474976710655 is out of range 0..=1048576"
+    );
+    assert_eq!(
+        error(".rept 0x1000\n.rept 0x1000\n.endr\n.endr\n"),
+        "line 2: macros and repetitions expand to more than 1048576 elements"
+    );
+}
+
+#[test]
+fn test_incbin() {
+    use crate::parser::SourceOptions;
+
+    let options = SourceOptions {
+        include_paths: vec!["./test/include".into()],
+        ..Default::default()
+    };
+    let assemble = |src: &str| assemble_with_options(src, &options);
+    let bin = assemble(
+        "table: .incbin \"table.bin\", 2, 4\n\
+         .incbin \"table.bin\", 6\n\
+         end:\n\
+         addi a0, zero, end - table\n",
+    )
+    .unwrap();
+    assert_eq!(bin[..6], [3, 4, 5, 6, 7, 8]);
+    assert_eq!(bin[8..], assemble("addi a0, zero, 6\n").unwrap()[..]);
+    assert_eq!(
+        assemble(".incbin \"table.bin\", 6, 3\n")
+            .unwrap_err()
+            .to_string(),
+        "line 1: '.incbin' of 3 bytes at offset 6 is past the end of the 8-byte file"
+    );
+}
+
+#[test]
+fn test_sections() {
+    use crate::emit::section::LayoutOptions;
+
+    let src = "addi a0, zero, value\n\
+               .bss\n\
+               buf: .space 16\n\
+               .data\n\
+               value: .space 2, 0x11\n\
+               .section .rodata\n\
+               .zero 1\n\
+               .text\n\
+               addi a1, zero, buf\n\
+               addi a2, zero, sizeof(buf)\n";
+    // .text, then .rodata at 0xc, .data at 0x10 and .bss after it without any bytes in the output
+    let mut expected =
+        assemble("addi a0, zero, 0x10\naddi a1, zero, 0x14\naddi a2, zero, 16\n").unwrap();
+    expected.extend_from_slice(&[0, 0, 0, 0, 0x11, 0x11]);
+    assert_eq!(assemble(src).unwrap(), expected);
+
+    let layout = LayoutOptions {
+        section_starts: vec![(".data".to_owned(), 0x20)],
+        ..Default::default()
+    };
+    let (bin, _) = assemble_with_layout(src, &layout).unwrap();
+    assert_eq!(bin.len(), 0x22);
+    assert_eq!(bin[..4], assemble("addi a0, zero, 0x20\n").unwrap()[..]);
+
+    let error = |src: &str| assemble(src).unwrap_err().to_string();
+    assert_eq!(
+        error(".bss\naddi a0, a0, 1\n"),
+        "line 2: 'addi' can't store bytes in '.bss', which only reserves space"
+    );
+    assert_eq!(
+        error("addi a0, a0, 1\n.data\n.org 2\n"),
+        "line 3: '.org' address 0x2 is before the start of section '.data'"
+    );
+    assert_eq!(
+        error(".data\n.space -1\n"),
+        "line 2: '.space' size -1 is negative"
+    );
+
+    // GNU section flags, types and entry sizes are accepted and ignored
+    assert_eq!(
+        assemble(
+            ".section .text.init, \"ax\", @progbits\naddi a0, a0, 1\n\
+             .section .rodata.str1.1,\"aMS\",%progbits,1\n.zero 1\n"
+        )
+        .unwrap(),
+        assemble("addi a0, a0, 1\n.rodata\n.zero 1\n").unwrap()
+    );
+    // reserving space doesn't take up memory for bytes that are never stored
+    assert_eq!(
+        assemble("addi a0, a0, 1\n.bss\n.space 0xfffffff\n")
+            .unwrap()
+            .len(),
+        4
+    );
+}
+
+#[test]
+fn test_alignment() {
+    let mut rv = rv32i();
+    let nop = [0x13, 0, 0, 0];
+    let bin = assemble_with(
+        &rv,
+        "addi a0, a0, 1\n\
+         .balign 16\n\
+         end:\n\
+         .data\n\
+         .space 1\n\
+         .p2align 3, 0xff\n\
+         .space 1\n\
+         .align 3, , 2\n\
+         addi a1, zero, end\n",
+    )
+    .unwrap();
+    assert_eq!(bin[4..16], [nop, nop, nop].concat()[..]);
+    // .data starts at 16, aligned to the 8 bytes requested in it
+    assert_eq!(bin[16..24], [0, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff]);
+    // 7 bytes of padding are more than the maximum of 2, the instruction only aligns to 4
+    assert_eq!(bin[24..28], [0, 0, 0, 0]);
+    assert_eq!(bin[28..], assemble("addi a1, zero, 16\n").unwrap()[..]);
+
+    // with compressed instructions 2-byte gaps get a c.nop
+    rv.load_single_cfg_string(
+        "[meta]\nname = \"c.nop\"\ncode = \"Zcnop\"\nspec = \"s\"\n\
+         [instruction_formats.CI]\n\
+         op = { type = \"value\", length = 2, encoding = [[1,0,0]] }\n\
+         rd = { type = \"register\", length = 5, encoding = [[4,0,7]] }\n\
+         funct3 = { type = \"value\", length = 3, encoding = [[2,0,13]] }\n\
+         [instructions.\"c.nop\"]\n\
+         format = \"CI\"\nargs = []\nfields = { op = 1, rd = 0, funct3 = 0 }\n",
+    )
+    .unwrap();
+    assert_eq!(
+        assemble_with(&rv, ".space 2\n.balign 8\n").unwrap(),
+        [0, 0, 1, 0, 0x13, 0, 0, 0]
+    );
+    assert_eq!(
+        assemble(".balign 12\n").unwrap_err().to_string(),
+        "line 1: '.balign' alignment 12 is not a power of two"
+    );
+    // not truncated to 2^0
+    assert_eq!(
+        assemble(".p2align 0x100000000\n").unwrap_err().to_string(),
+        "line 1: '.p2align' power 4294967296 out of range 0..=63"
+    );
+    assert_eq!(
+        assemble(".align 64\n").unwrap_err().to_string(),
+        "line 1: '.align' power 64 out of range 0..=63"
+    );
+    // instead of running out of memory for the padding
+    assert_eq!(
+        assemble("addi a0, a0, 1\n.balign 0x100000000000\n")
+            .unwrap_err()
+            .to_string(),
+        "line 2: '.balign' alignment 0x100000000000 is larger than the maximum of 0x10000000"
+    );
+    assert_eq!(
+        assemble(".org 0x10000004\n").unwrap_err().to_string(),
+        "line 1: section '.text' would grow to 0x10000004 bytes, more than the maximum of 0x10000000"
+    );
+}
+
+#[test]
+fn test_base_address() {
+    use crate::emit::section::LayoutOptions;
+
+    let layout = LayoutOptions {
+        base_address: 0x8000_0000,
+        ..Default::default()
+    };
+    let bin = assemble_with_layout(
+        ".org 0x80000010\n\
+         start: addi a0, zero, start & 0xff\n\
+         .phase 0x100\n\
+         ram: addi a1, zero, later\n\
+         later: addi a2, zero, ram\n\
+         .dephase\n\
+         end: addi a3, zero, end & 0xff\n",
+        &layout,
+    )
+    .unwrap()
+    .0;
+    // the code in the .phase block is stored after `start` but runs at 0x100
+    let expected = assemble(
+        "addi a0, zero, 0x10\naddi a1, zero, 0x104\naddi a2, zero, 0x100\naddi a3, zero, 0x1c\n",
+    )
+    .unwrap();
+    assert_eq!(bin[..0x10], [0; 0x10]);
+    assert_eq!(bin[0x10..], expected[..]);
+
+    assert_eq!(
+        assemble_with_layout(".org 0x10\n", &layout)
+            .unwrap_err()
+            .to_string(),
+        "line 1: '.org' address 0x10 is before the start of section '.text'"
+    );
+    let layout = LayoutOptions {
+        section_starts: vec![(".data".to_owned(), 0x100)],
+        base_address: 0x1000,
+        ..Default::default()
+    };
+    assert_eq!(
+        assemble_with_layout(".data\n.zero 4\n", &layout)
+            .unwrap_err()
+            .to_string(),
+        "section '.data' starts at 0x100, before the base address 0x1000"
+    );
+}
+
+#[test]
+fn test_overlap() {
+    use crate::emit::section::LayoutOptions;
+
+    // the vector at 0x100 waits for `later` and the code at 0xfc runs into it
+    let src = ".org 0x100\n\
+               addi a0, zero, later\n\
+               .org 0xfc\n\
+               addi a1, a1, 1\n\
+               addi a2, a2, 1\n\
+               later:\n";
+    assert_eq!(
+        assemble(src).unwrap_err().to_string(),
+        "line 5: overwrites the byte at 0x100 already stored at line 2"
+    );
+
+    let layout = LayoutOptions {
+        allow_overlap: true,
+        ..Default::default()
+    };
+    let (bin, warnings) = assemble_with_layout(src, &layout).unwrap();
+    let warnings: Vec<String> = warnings.iter().map(|w| w.to_string()).collect();
+    assert_eq!(
+        warnings,
+        ["line 5: overwrites the byte at 0x100 already stored at line 2"]
+    );
+    // the bytes stored later are kept
+    let expected = assemble("addi a2, a2, 1\n").unwrap();
+    assert_eq!(bin[0x100..], expected[..]);
+
+    // a waiting instruction only fills in the bytes that weren't overwritten since
+    let (bin, warnings) = assemble_with_layout(
+        "addi a0, zero, later\n.org 2\n.space 2, 0xee\nlater:\n",
+        &layout,
+    )
+    .unwrap();
+    assert_eq!(warnings.len(), 1);
+    let expected = assemble("addi a0, zero, 4\n").unwrap();
+    assert_eq!(bin, [expected[0], expected[1], 0xee, 0xee]);
+    // also when the bytes were overwritten by a copy of the same line
+    let (bin, warnings) = assemble_with_layout(
+        ".irp v, later, 5\n.org 0\naddi a0, zero, \\v\n.endr\nlater:\n",
+        &layout,
+    )
+    .unwrap();
+    assert_eq!(warnings.len(), 1);
+    assert_eq!(bin, assemble("addi a0, zero, 5\n").unwrap());
+
+    // sections and alignment padding don't count as overlaps
+    assert!(
+        assemble(".data\n.space 2\n.text\naddi a0, a0, 1\n.data\n.balign 4\n.space 4\n").is_ok()
+    );
+
+    // sections placed over each other with --section-start are an error
+    let src = "addi a0, a0, 1\naddi a1, a1, 1\naddi a2, a2, 1\n.data\n.space 4, 0xee\n";
+    let mut layout = LayoutOptions {
+        section_starts: vec![(".data".to_owned(), 4)],
+        ..Default::default()
+    };
+    assert_eq!(
+        assemble_with_layout(src, &layout).unwrap_err().to_string(),
+        "line 5: section '.data' overwrites the byte at 0x4 stored by section '.text'"
+    );
+    // or a warning with --allow-overlap, and the section appearing later is kept
+    layout.allow_overlap = true;
+    let (bin, warnings) = assemble_with_layout(src, &layout).unwrap();
+    assert_eq!(warnings.len(), 1);
+    assert_eq!(bin[4..8], [0xee; 4]);
+    assert_eq!(bin.len(), 12);
+    // sections placed right after each other get no warning
+    layout.section_starts[0].1 = 12;
+    assert!(assemble_with_layout(src, &layout).unwrap().1.is_empty());
+}
@@ -0,0 +1,2 @@
+addi a0, zero, 1
+.include "cycle_b.s"
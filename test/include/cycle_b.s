@@ -0,0 +1,2 @@
+addi a0, zero, 2
+.include "cycle_a.s"
@@ -0,0 +1,4 @@
+.equ COUNT, 3
+.macro zero_reg rd
+    addi \rd, zero, 0
+.endm
@@ -0,0 +1 @@
+addi x0, x0, 0
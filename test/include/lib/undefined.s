@@ -0,0 +1,2 @@
+; refers to a symbol that isn't defined anywhere, after a long comment
+addi a1, zero, first
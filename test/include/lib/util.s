@@ -0,0 +1,3 @@
+clear:
+    zero_reg a1
+    jalr zero, ra, 0
@@ -0,0 +1,5 @@
+.include "defs.s"
+start:
+    addi a0, zero, COUNT
+.include "util.s"
+    jal ra, clear